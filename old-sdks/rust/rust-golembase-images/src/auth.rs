@@ -0,0 +1,197 @@
+use crate::{error::AppError, AppState};
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, Method},
+    middleware::Next,
+    response::Response,
+};
+use golem_base_sdk::entity::Annotation;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who made a request, as established by `authenticate`. Stored as the `uploader` annotation
+/// on the entities the request creates.
+#[derive(Clone, Debug)]
+pub struct Identity(pub String);
+
+impl Identity {
+    pub fn annotation(&self) -> Annotation<String> {
+        Annotation::new("uploader", self.0.clone())
+    }
+}
+
+/// The credentials accepted by `authenticate`: fixed API keys, each with the identity it
+/// stands for, and/or a secret that signed tokens are checked against.
+/// With neither configured, authentication is off and every request is anonymous.
+pub struct Auth {
+    pub api_keys: Vec<(String, String)>,
+    pub hmac_secret: Option<String>,
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || self.hmac_secret.is_some()
+    }
+
+    /// Resolves a bearer token to an identity: either one of the API keys, or a signed token
+    /// `<identity>:<expires_at>:<signature>` (see `issue_token`) that hasn't expired yet.
+    pub fn verify(&self, token: &str) -> Option<Identity> {
+        if let Some((identity, _)) = self.api_keys.iter().find(|(_, key)| constant_time_eq(key.as_bytes(), token.as_bytes())) {
+            return Some(Identity(identity.clone()));
+        }
+
+        let secret = self.hmac_secret.as_ref()?;
+        let (payload, signature) = token.rsplit_once(':')?;
+        let (identity, expires_at) = payload.split_once(':')?;
+        let expires_at: u64 = expires_at.parse().ok()?;
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&hex::decode(signature).ok()?).ok()?;
+        (!identity.is_empty() && expires_at > unix_now()).then(|| Identity(identity.to_string()))
+    }
+}
+
+/// Creates a token for `identity` that is valid for `valid_for` seconds, signed with `secret`.
+pub fn issue_token(secret: &str, identity: &str, valid_for: u64) -> Result<String, String> {
+    if identity.is_empty() || identity.contains(':') {
+        return Err("identity must be non-empty and must not contain ':'".to_string());
+    }
+    let payload = format!("{}:{}", identity, unix_now() + valid_for);
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).map_err(|e| e.to_string())?;
+    mac.update(payload.as_bytes());
+    Ok(format!("{}:{}", payload, hex::encode(mac.finalize().into_bytes())))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// The token of an `Authorization` header value, if it is `Bearer <token>`.
+fn bearer_token(value: &str) -> Option<&str> {
+    value.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Compares two secrets without giving away through timing how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Middleware that checks the `Authorization: Bearer <token>` header and makes the caller's
+/// `Identity` available to the handlers as a request extension.
+///
+/// Requests that change something always need a valid token. Reads (GET and HEAD) need one
+/// too unless `public_reads` is on, in which case they may be anonymous; a token that is sent
+/// anyway must still be valid. The upload form at `/` is always public.
+pub async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !state.auth.is_enabled() || request.uri().path() == "/" {
+        return Ok(next.run(request).await);
+    }
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token);
+    match token {
+        Some(token) => {
+            let identity = state
+                .auth
+                .verify(token)
+                .ok_or_else(|| AppError::Unauthorized("Invalid or expired token.".to_string()))?;
+            request.extensions_mut().insert(identity);
+        }
+        None => {
+            let is_read = matches!(*request.method(), Method::GET | Method::HEAD);
+            if !(is_read && state.config.public_reads) {
+                return Err(AppError::Unauthorized("This endpoint needs an Authorization: Bearer token.".to_string()));
+            }
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "a-test-secret-that-is-long-enough-for-hmac";
+
+    fn signed_only() -> Auth {
+        Auth {
+            api_keys: vec![],
+            hmac_secret: Some(SECRET.to_string()),
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_signed_token() {
+        let token = issue_token(SECRET, "alice", 60).unwrap();
+        assert_eq!(signed_only().verify(&token).map(|identity| identity.0), Some("alice".to_string()));
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let token = issue_token(SECRET, "alice", 0).unwrap();
+        assert!(signed_only().verify(&token).is_none());
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature() {
+        let token = issue_token(SECRET, "alice", 60).unwrap();
+        let last = if token.ends_with('0') { '1' } else { '0' };
+        let tampered = format!("{}{}", &token[..token.len() - 1], last);
+        assert!(signed_only().verify(&tampered).is_none());
+    }
+
+    #[test]
+    fn rejects_a_token_for_another_identity_or_secret() {
+        let token = issue_token(SECRET, "alice", 60).unwrap();
+        let (_, rest) = token.split_once(':').unwrap();
+        assert!(signed_only().verify(&format!("mallory:{}", rest)).is_none());
+
+        let other = issue_token("another-secret-that-is-long-enough", "alice", 60).unwrap();
+        assert!(signed_only().verify(&other).is_none());
+    }
+
+    #[test]
+    fn identities_cannot_contain_colons() {
+        assert!(issue_token(SECRET, "alice:9999999999", 60).is_err());
+        assert!(issue_token(SECRET, "", 60).is_err());
+
+        // A token whose identity was made to contain a colon doesn't parse either.
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(b"alice:x:9999999999");
+        let token = format!("alice:x:9999999999:{}", hex::encode(mac.finalize().into_bytes()));
+        assert!(signed_only().verify(&token).is_none());
+    }
+
+    #[test]
+    fn checks_api_keys() {
+        let auth = Auth {
+            api_keys: vec![("alice".to_string(), "key-of-alice".to_string())],
+            hmac_secret: None,
+        };
+        assert_eq!(auth.verify("key-of-alice").map(|identity| identity.0), Some("alice".to_string()));
+        assert!(auth.verify("key-of-bob").is_none());
+        assert!(auth.verify("key-of-alic").is_none());
+        assert!(auth.verify("").is_none());
+    }
+
+    #[test]
+    fn needs_the_bearer_prefix() {
+        assert_eq!(bearer_token("Bearer abc "), Some("abc"));
+        assert_eq!(bearer_token("abc"), None);
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("bearer abc"), None);
+    }
+}
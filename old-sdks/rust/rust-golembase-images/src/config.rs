@@ -0,0 +1,337 @@
+use crate::{rendition::Rendition, upload::SUPPORTED_TYPES};
+use clap::{Parser, Subcommand};
+use dirs::config_dir;
+use golem_base_sdk::Url;
+use serde::Deserialize;
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
+
+/// Command line flags. Every flag can also be set through the environment variable
+/// named next to it, and falls back to the TOML config file and then to the defaults.
+#[derive(Parser)]
+#[command(about = "Golem Base image server")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML file to read settings from [default: ./config.toml, if it exists]
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Golem Base JSON-RPC endpoint [default: http://localhost:8545]
+    #[arg(long, env = "GOLEM_RPC")]
+    rpc_url: Option<Url>,
+    /// Address the HTTP server listens on [default: 127.0.0.1:3000]
+    #[arg(long, env = "BIND_ADDR")]
+    bind_address: Option<SocketAddr>,
+    /// Number of blocks new entities live for [default: 25]
+    #[arg(long, env = "ENTITY_BTL")]
+    btl: Option<u64>,
+    /// Longest lifetime in blocks an upload's `btl` or `/extend` may give an entity [default: 43200]
+    #[arg(long, env = "MAX_BTL")]
+    max_btl: Option<u64>,
+    /// Size in bytes of each stored image chunk [default: 100000]
+    #[arg(long, env = "CHUNK_SIZE")]
+    chunk_size: Option<usize>,
+    /// Value of the `app` annotation on every entity we create and query [default: golem-images-0.1]
+    #[arg(long, env = "APP_TAG")]
+    app_tag: Option<String>,
+    /// Encrypted wallet to sign with [default: <config dir>/golembase/wallet.json]
+    #[arg(long, env = "KEYSTORE_PATH")]
+    keystore_path: Option<PathBuf>,
+    /// Smallest width/height accepted by /add-resize [default: 16]
+    #[arg(long, env = "RESIZE_MIN_DIM")]
+    resize_min_dim: Option<u32>,
+    /// Largest width/height accepted by /add-resize [default: 4096]
+    #[arg(long, env = "RESIZE_MAX_DIM")]
+    resize_max_dim: Option<u32>,
+    /// How many chunk entities are fetched from the node at the same time [default: 8]
+    #[arg(long, env = "CHUNK_FETCH_CONCURRENCY")]
+    chunk_fetch_concurrency: Option<usize>,
+    /// Seconds between reads of the node's storage logs for new, deleted and expired images pushed to GET /events [default: 2]
+    #[arg(long, env = "EVENTS_POLL_INTERVAL")]
+    events_poll_interval: Option<u64>,
+    /// Comma-separated thumbnails to generate for every upload, as WIDTHxHEIGHT:FIT[:FORMAT[:QUALITY]]
+    /// with FIT one of crop, fit or exact and FORMAT one of jpeg, png, webp or avif [default: 100x100:crop]
+    #[arg(long, env = "RENDITIONS", value_delimiter = ',')]
+    renditions: Option<Vec<Rendition>>,
+    /// Comma-separated image types accepted by /upload, detected from the file contents
+    /// [default: image/jpeg,image/png,image/webp]
+    #[arg(long, env = "ALLOWED_TYPES", value_delimiter = ',')]
+    allowed_types: Option<Vec<String>>,
+    /// Largest image accepted by /upload, in bytes [default: 20000000]
+    #[arg(long, env = "MAX_UPLOAD_SIZE")]
+    max_upload_size: Option<usize>,
+    /// Most chunk entities a single upload may be split into [default: 250]
+    #[arg(long, env = "MAX_CHUNKS")]
+    max_chunks: Option<u64>,
+    /// Requests per minute each client IP may make on average [default: 120]
+    #[arg(long, env = "RATE_LIMIT_PER_MINUTE")]
+    rate_limit_per_minute: Option<u32>,
+    /// Requests a client IP may make in a burst before being rate limited [default: 30]
+    #[arg(long, env = "RATE_LIMIT_BURST")]
+    rate_limit_burst: Option<u32>,
+    /// Bytes each client IP may upload per day (UTC), 0 for no limit [default: 500000000]
+    #[arg(long, env = "DAILY_UPLOAD_QUOTA")]
+    daily_upload_quota: Option<u64>,
+    /// Comma-separated API keys accepted as bearer tokens, as IDENTITY=KEY
+    #[arg(long, env = "API_KEYS", value_delimiter = ',')]
+    api_keys: Option<Vec<String>>,
+    /// Whether GET requests are allowed without a token when authentication is on [default: true]
+    #[arg(long, env = "PUBLIC_READS")]
+    public_reads: Option<bool>,
+}
+
+/// Maintenance tasks that run instead of the server.
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Rewrite comma-joined `tag` annotations into one lower-cased `tag` annotation per tag
+    MigrateTags {
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print a bearer token for IDENTITY, signed with the secret in AUTH_HMAC_SECRET
+    IssueToken {
+        identity: String,
+        /// How long the token stays valid, in seconds
+        #[arg(long, default_value_t = 30 * 24 * 60 * 60)]
+        valid_for: u64,
+    },
+}
+
+/// The same settings as `Cli`, as they appear in the TOML file.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    rpc_url: Option<String>,
+    bind_address: Option<SocketAddr>,
+    btl: Option<u64>,
+    max_btl: Option<u64>,
+    chunk_size: Option<usize>,
+    app_tag: Option<String>,
+    keystore_path: Option<PathBuf>,
+    resize_min_dim: Option<u32>,
+    resize_max_dim: Option<u32>,
+    chunk_fetch_concurrency: Option<usize>,
+    events_poll_interval: Option<u64>,
+    renditions: Option<Vec<String>>,
+    allowed_types: Option<Vec<String>>,
+    max_upload_size: Option<usize>,
+    max_chunks: Option<u64>,
+    rate_limit_per_minute: Option<u32>,
+    rate_limit_burst: Option<u32>,
+    daily_upload_quota: Option<u64>,
+    /// Identity = key.
+    api_keys: Option<BTreeMap<String, String>>,
+    public_reads: Option<bool>,
+}
+
+/// The smallest and largest width/height (in pixels) we accept for `/add-resize/:id`.
+#[derive(Clone, Copy)]
+pub struct ResizeLimits {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl ResizeLimits {
+    pub fn contains(&self, dim: u32) -> bool {
+        (self.min..=self.max).contains(&dim)
+    }
+}
+
+/// The validated server configuration, shared with every handler through `AppState`.
+pub struct Config {
+    pub rpc_url: Url,
+    pub bind_address: SocketAddr,
+    pub btl: u64,
+    /// The longest lifetime, in blocks from now, that an upload or `/extend` may give an entity.
+    pub max_btl: u64,
+    pub chunk_size: usize,
+    pub app_tag: String,
+    pub keystore_path: PathBuf,
+    pub resize_limits: ResizeLimits,
+    pub chunk_fetch_concurrency: usize,
+    /// Seconds between the reads of the storage logs behind `GET /events`.
+    pub events_poll_interval: u64,
+    /// The thumbnails generated for every upload. The first one is the gallery thumbnail.
+    pub renditions: Vec<Rendition>,
+    /// The image types accepted by `/upload`.
+    pub allowed_types: Vec<String>,
+    /// The largest image accepted by `/upload`, in bytes.
+    pub max_upload_size: usize,
+    /// The most chunk entities a single upload may be split into.
+    pub max_chunks: u64,
+    pub rate_limit_per_minute: u32,
+    pub rate_limit_burst: u32,
+    /// Bytes each client IP may upload per day; 0 means no limit.
+    pub daily_upload_quota: u64,
+    /// API keys accepted as bearer tokens, as (identity, key) pairs.
+    pub api_keys: Vec<(String, String)>,
+    /// The secret signed bearer tokens are checked against. Only read from the environment.
+    pub auth_hmac_secret: Option<String>,
+    /// Whether reads are allowed without a token when authentication is on.
+    pub public_reads: bool,
+    /// The maintenance task to run instead of the server, if any.
+    pub command: Option<Command>,
+}
+
+impl Config {
+    /// Builds the configuration from CLI flags, environment variables and the config file,
+    /// in that order of precedence, and checks that the result makes sense.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let cli = Cli::parse();
+
+        let file = match &cli.config {
+            Some(path) => Self::read_file(path)?,
+            None => {
+                let default_path = PathBuf::from("config.toml");
+                if default_path.exists() {
+                    Self::read_file(&default_path)?
+                } else {
+                    FileConfig::default()
+                }
+            }
+        };
+
+        let keystore_path = match cli.keystore_path.or(file.keystore_path) {
+            Some(path) => path,
+            None => config_dir()
+                .ok_or("Failed to get config directory")?
+                .join("golembase")
+                .join("wallet.json"),
+        };
+
+        let renditions = match (cli.renditions, file.renditions) {
+            (Some(renditions), _) => renditions,
+            (None, Some(renditions)) => renditions
+                .iter()
+                .map(|rendition| rendition.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("Invalid renditions: {}", e))?,
+            (None, None) => vec!["100x100:crop".parse()?],
+        };
+
+        let api_keys = match (cli.api_keys, file.api_keys) {
+            (Some(keys), _) => keys
+                .iter()
+                .map(|entry| {
+                    entry
+                        .split_once('=')
+                        .map(|(identity, key)| (identity.trim().to_string(), key.trim().to_string()))
+                        .ok_or_else(|| format!("Invalid api_keys entry {:?}, expected IDENTITY=KEY", entry))
+                })
+                .collect::<Result<_, _>>()?,
+            (None, Some(keys)) => keys.into_iter().collect(),
+            (None, None) => vec![],
+        };
+
+        let config = Config {
+            rpc_url: match (cli.rpc_url, file.rpc_url) {
+                (Some(url), _) => url,
+                (None, Some(url)) => Url::parse(&url).map_err(|e| format!("Invalid rpc_url {:?}: {}", url, e))?,
+                (None, None) => Url::parse("http://localhost:8545")?,
+            },
+            bind_address: cli
+                .bind_address
+                .or(file.bind_address)
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+            btl: cli.btl.or(file.btl).unwrap_or(25),
+            max_btl: cli.max_btl.or(file.max_btl).unwrap_or(43_200),
+            chunk_size: cli.chunk_size.or(file.chunk_size).unwrap_or(100000),
+            app_tag: cli
+                .app_tag
+                .or(file.app_tag)
+                .unwrap_or_else(|| "golem-images-0.1".to_string()),
+            keystore_path,
+            resize_limits: ResizeLimits {
+                min: cli.resize_min_dim.or(file.resize_min_dim).unwrap_or(16),
+                max: cli.resize_max_dim.or(file.resize_max_dim).unwrap_or(4096),
+            },
+            chunk_fetch_concurrency: cli
+                .chunk_fetch_concurrency
+                .or(file.chunk_fetch_concurrency)
+                .unwrap_or(8),
+            events_poll_interval: cli.events_poll_interval.or(file.events_poll_interval).unwrap_or(2),
+            renditions,
+            allowed_types: cli
+                .allowed_types
+                .or(file.allowed_types)
+                .unwrap_or_else(|| SUPPORTED_TYPES.iter().map(|mime| mime.to_string()).collect()),
+            max_upload_size: cli.max_upload_size.or(file.max_upload_size).unwrap_or(20_000_000),
+            max_chunks: cli.max_chunks.or(file.max_chunks).unwrap_or(250),
+            rate_limit_per_minute: cli
+                .rate_limit_per_minute
+                .or(file.rate_limit_per_minute)
+                .unwrap_or(120),
+            rate_limit_burst: cli.rate_limit_burst.or(file.rate_limit_burst).unwrap_or(30),
+            daily_upload_quota: cli
+                .daily_upload_quota
+                .or(file.daily_upload_quota)
+                .unwrap_or(500_000_000),
+            api_keys,
+            auth_hmac_secret: std::env::var("AUTH_HMAC_SECRET").ok().filter(|secret| !secret.is_empty()),
+            public_reads: cli.public_reads.or(file.public_reads).unwrap_or(true),
+            command: cli.command,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read_file(path: &PathBuf) -> Result<FileConfig, Box<dyn std::error::Error>> {
+        println!("Reading configuration from {:?}", path);
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {:?}: {}", path, e))?;
+        Ok(toml::from_str(&text).map_err(|e| format!("Invalid config file {:?}: {}", path, e))?)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.btl == 0 {
+            return Err("btl must be at least 1 block".into());
+        }
+        if self.btl > self.max_btl {
+            return Err("btl must not be larger than max_btl".into());
+        }
+        if self.chunk_size == 0 {
+            return Err("chunk_size must be at least 1 byte".into());
+        }
+        if self.app_tag.trim().is_empty() {
+            return Err("app_tag must not be empty".into());
+        }
+        if self.resize_limits.min == 0 || self.resize_limits.min > self.resize_limits.max {
+            return Err("resize_min_dim must be at least 1 and not larger than resize_max_dim".into());
+        }
+        if self.chunk_fetch_concurrency == 0 {
+            return Err("chunk_fetch_concurrency must be at least 1".into());
+        }
+        if self.events_poll_interval == 0 {
+            return Err("events_poll_interval must be at least 1 second".into());
+        }
+        if self.renditions.is_empty() {
+            return Err("renditions must list at least one thumbnail".into());
+        }
+        if self.allowed_types.is_empty() {
+            return Err("allowed_types must list at least one image type".into());
+        }
+        if let Some(mime) = self.allowed_types.iter().find(|mime| !SUPPORTED_TYPES.contains(&mime.as_str())) {
+            return Err(format!("allowed_types: {} can't be decoded, supported are {}", mime, SUPPORTED_TYPES.join(", ")).into());
+        }
+        if self.max_upload_size == 0 {
+            return Err("max_upload_size must be at least 1 byte".into());
+        }
+        if self.max_chunks == 0 {
+            return Err("max_chunks must be at least 1".into());
+        }
+        if self.rate_limit_per_minute == 0 || self.rate_limit_burst == 0 {
+            return Err("rate_limit_per_minute and rate_limit_burst must be at least 1".into());
+        }
+        if let Some((identity, _)) = self
+            .api_keys
+            .iter()
+            .find(|(identity, key)| identity.is_empty() || identity.contains(':') || key.len() < 16)
+        {
+            return Err(format!("api_keys: {:?} needs a non-empty identity without ':' and a key of at least 16 characters", identity).into());
+        }
+        if self.auth_hmac_secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            return Err("AUTH_HMAC_SECRET must be at least 32 characters long".into());
+        }
+        Ok(())
+    }
+}
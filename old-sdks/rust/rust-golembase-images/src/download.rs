@@ -0,0 +1,364 @@
+use crate::{query::Query, AppState};
+use alloy_primitives::Address;
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use golem_base_sdk::{GolemBaseClient, Hash};
+use sha2::{Digest, Sha256};
+use std::{fmt, ops::RangeInclusive, sync::Arc};
+
+pub type DownloadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Chunk size used by images uploaded before the `chunk_size` annotation existed.
+const LEGACY_CHUNK_SIZE: u64 = 100000;
+
+/// The stored chunks of an image don't add up to the image that was uploaded.
+#[derive(Debug)]
+pub enum IntegrityError {
+    MissingChunk { id: Hash, part: u64 },
+    DuplicateChunk { id: Hash, part: u64 },
+    ChunkChecksumMismatch { id: Hash, part: u64 },
+    ChecksumMismatch { id: Hash },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::MissingChunk { id, part } => write!(f, "chunk {} of image {} is missing", part, id),
+            IntegrityError::DuplicateChunk { id, part } => write!(f, "chunk {} of image {} is stored more than once", part, id),
+            IntegrityError::ChunkChecksumMismatch { id, part } => write!(f, "chunk {} of image {} failed its SHA-256 check", part, id),
+            IntegrityError::ChecksumMismatch { id } => write!(f, "image {} failed its SHA-256 check", id),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Helper function that returns the lowercase hex SHA-256 of `data`, as stored in the `sha256` annotations.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// A chunk entity and the checksum it was stored with, if any.
+pub struct ChunkRef {
+    pub part: u64,
+    pub key: Hash,
+    pub sha256: Option<String>,
+}
+
+/// Everything we need to know about a stored image to serve all or part of it.
+pub struct ImageLayout {
+    pub id: Hash,
+    pub filename: String,
+    pub mime_type: String,
+    pub parts: u64,
+    pub chunk_size: u64,
+    /// Total size in bytes. Images uploaded before the `size` annotation existed don't have it.
+    pub size: Option<u64>,
+    /// SHA-256 of the whole image and of the first chunk, for images uploaded with checksums.
+    pub sha256: Option<String>,
+    pub first_chunk_sha256: Option<String>,
+    pub tags: Vec<String>,
+    /// The block at which the main entity expires.
+    pub expires_at_block: u64,
+    /// The owner of the main entity. Only chunks with the same owner belong to the image.
+    pub owner: Address,
+}
+
+impl ImageLayout {
+    /// Reads the layout from the main entity's annotations.
+    pub async fn load(client: &GolemBaseClient, id: Hash) -> Result<Self, DownloadError> {
+        let metadata = client.get_entity_metadata(id).await?;
+
+        let mut layout = ImageLayout {
+            id,
+            filename: "image".to_string(),
+            mime_type: "application/octet-stream".to_string(),
+            parts: 1,
+            chunk_size: LEGACY_CHUNK_SIZE,
+            size: None,
+            sha256: None,
+            first_chunk_sha256: None,
+            tags: vec![],
+            expires_at_block: metadata.expires_at_block,
+            owner: metadata.owner,
+        };
+        for annot in metadata.string_annotations {
+            match annot.key.as_str() {
+                "filename" => layout.filename = annot.value,
+                "mime_type" => layout.mime_type = annot.value,
+                "sha256" => layout.sha256 = Some(annot.value),
+                "chunk_sha256" => layout.first_chunk_sha256 = Some(annot.value),
+                "tag" => layout.tags.push(annot.value),
+                _ => {}
+            }
+        }
+        for annot in metadata.numeric_annotations {
+            match annot.key.as_str() {
+                "part_of" => layout.parts = annot.value.max(1),
+                "chunk_size" => layout.chunk_size = annot.value.max(1),
+                "size" => layout.size = Some(annot.value),
+                _ => {}
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Returns the total size, fetching the last chunk to work it out if it wasn't annotated.
+    pub async fn resolve_size(&mut self, state: &AppState) -> Result<u64, DownloadError> {
+        if let Some(size) = self.size {
+            return Ok(size);
+        }
+        let last = chunk_keys(state, self, self.parts..=self.parts).await?;
+        let last = state.client.get_storage_value::<Vec<u8>>(last[0].key).await?;
+        let size = (self.parts - 1) * self.chunk_size + last.len() as u64;
+        self.size = Some(size);
+        Ok(size)
+    }
+}
+
+/// Finds the entities holding `parts` with a single query, returning them in part order.
+/// Part 1 is stored on the main entity itself. The query results don't carry annotations,
+/// so each chunk's `part` and checksum are read from its metadata, at most
+/// `chunk_fetch_concurrency` at a time.
+/// Anyone can create an entity claiming to be a chunk of any image, so chunks that aren't
+/// owned by the image's owner are ignored.
+/// Fails with an `IntegrityError` if a part is missing or stored more than once.
+pub async fn chunk_keys(
+    state: &AppState,
+    layout: &ImageLayout,
+    parts: RangeInclusive<u64>,
+) -> Result<Vec<ChunkRef>, DownloadError> {
+    let client = &state.client;
+    let mut chunks = vec![];
+    let mut first = *parts.start();
+    if first == 1 {
+        chunks.push(ChunkRef {
+            part: 1,
+            key: layout.id,
+            sha256: layout.first_chunk_sha256.clone(),
+        });
+        first = 2;
+    }
+
+    if first <= *parts.end() {
+        let query = Query::eq("parent", layout.id.to_string())
+            .and(Query::eq("type", "image_chunk"))
+            .and(Query::eq("app", state.config.app_tag.as_str()))
+            .and(Query::ge("part", first))
+            .and(Query::le("part", *parts.end()));
+        println!("Querying for chunks {}..={}: {}", first, parts.end(), query);
+        let found = client.query_entity_keys(&query.to_string()).await?;
+
+        let numbered: Vec<Option<ChunkRef>> = stream::iter(found)
+            .map(|key| async move {
+                let metadata = client.get_entity_metadata(key).await?;
+                if metadata.owner != layout.owner {
+                    println!("Ignoring chunk {} of {}: owned by {}", key, layout.id, metadata.owner);
+                    return Ok(None);
+                }
+                let part = metadata
+                    .numeric_annotations
+                    .iter()
+                    .find(|annot| annot.key == "part")
+                    .map(|annot| annot.value)
+                    .ok_or_else(|| format!("chunk {} has no part annotation", key))?;
+                let sha256 = metadata
+                    .string_annotations
+                    .into_iter()
+                    .find(|annot| annot.key == "chunk_sha256")
+                    .map(|annot| annot.value);
+                Ok::<_, DownloadError>(Some(ChunkRef { part, key, sha256 }))
+            })
+            .buffer_unordered(state.config.chunk_fetch_concurrency)
+            .try_collect()
+            .await?;
+        let mut numbered: Vec<ChunkRef> = numbered.into_iter().flatten().collect();
+        numbered.sort_by_key(|chunk| chunk.part);
+        chunks.extend(numbered);
+    }
+
+    for wanted in parts {
+        match chunks.iter().filter(|chunk| chunk.part == wanted).count() {
+            0 => return Err(IntegrityError::MissingChunk { id: layout.id, part: wanted }.into()),
+            1 => {}
+            _ => return Err(IntegrityError::DuplicateChunk { id: layout.id, part: wanted }.into()),
+        }
+    }
+    Ok(chunks)
+}
+
+/// Fetches the storage values of `chunks` with at most `chunk_fetch_concurrency` requests
+/// in flight, yielding them in the same order as `chunks`. Each chunk stored with a checksum
+/// is verified against it.
+pub fn fetch_chunks(
+    state: Arc<AppState>,
+    id: Hash,
+    chunks: Vec<ChunkRef>,
+) -> impl Stream<Item = Result<(u64, Bytes), DownloadError>> {
+    let concurrency = state.config.chunk_fetch_concurrency;
+    stream::iter(chunks)
+        .map(move |chunk| {
+            let state = state.clone();
+            async move {
+                let data = state.client.get_storage_value::<Vec<u8>>(chunk.key).await?;
+                if chunk.sha256.is_some_and(|expected| expected != sha256_hex(&data)) {
+                    return Err(IntegrityError::ChunkChecksumMismatch { id, part: chunk.part }.into());
+                }
+                Ok((chunk.part, Bytes::from(data)))
+            }
+        })
+        .buffered(concurrency)
+}
+
+/// Parses a `Range` header into an inclusive byte range within `size`.
+///
+/// Only a single `bytes=` range is supported; anything else (including multiple ranges and
+/// malformed ones) yields `Ok(None)` and the whole image is served instead, as RFC 9110 allows.
+/// `Err(())` means the range can't be satisfied and the caller should answer 416.
+pub fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let number = |value: &str| value.parse::<u64>().ok();
+
+    let range = match (start.is_empty(), end.is_empty()) {
+        // bytes=-N: the last N bytes
+        (true, false) => {
+            let Some(suffix) = number(end) else {
+                return Ok(None);
+            };
+            if suffix == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        // bytes=N-: everything from N
+        (false, true) => match number(start) {
+            Some(start) => (start, size.saturating_sub(1)),
+            None => return Ok(None),
+        },
+        // bytes=N-M
+        (false, false) => match (number(start), number(end)) {
+            (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            _ => return Ok(None),
+        },
+        (true, true) => return Ok(None),
+    };
+
+    if size == 0 || range.0 >= size {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// Works out which parts cover the inclusive byte `range` (or all parts without one).
+pub fn parts_for_range(layout: &ImageLayout, range: Option<(u64, u64)>) -> RangeInclusive<u64> {
+    match range {
+        Some((start, end)) => {
+            (start / layout.chunk_size + 1)..=(end / layout.chunk_size + 1).min(layout.parts)
+        }
+        None => 1..=layout.parts,
+    }
+}
+
+/// Streams the image in `part` order from the `chunks` returned by `chunk_keys`,
+/// prefetching up to `chunk_fetch_concurrency` chunks ahead. With a `range`, the first and last
+/// chunks are trimmed to that inclusive byte range.
+///
+/// Headers are already sent by the time a chunk fails its checksum, so in that case the
+/// stream ends with an error and the client sees an aborted body.
+pub fn stream_image(
+    state: Arc<AppState>,
+    layout: &ImageLayout,
+    chunks: Vec<ChunkRef>,
+    range: Option<(u64, u64)>,
+) -> impl Stream<Item = Result<Bytes, DownloadError>> + use<> {
+    let chunk_size = layout.chunk_size;
+
+    fetch_chunks(state, layout.id, chunks).map_ok(move |(part, data)| {
+        let Some((start, end)) = range else {
+            return data;
+        };
+        let part_start = (part - 1) * chunk_size;
+        let from = (start.saturating_sub(part_start) as usize).min(data.len());
+        let to = ((end + 1 - part_start) as usize).min(data.len());
+        data.slice(from..to.max(from))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(parts: u64, chunk_size: u64) -> ImageLayout {
+        ImageLayout {
+            id: Hash::ZERO,
+            filename: "image.png".to_string(),
+            mime_type: "image/png".to_string(),
+            parts,
+            chunk_size,
+            size: None,
+            sha256: None,
+            first_chunk_sha256: None,
+            tags: vec![],
+            expires_at_block: 0,
+            owner: Address::ZERO,
+        }
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-0", 100), Ok(Some((0, 0))));
+        assert_eq!(parse_range("bytes=10-19", 100), Ok(Some((10, 19))));
+        assert_eq!(parse_range(" bytes= 10 - 19 ", 100), Ok(Some((10, 19))));
+        // The end is clamped to the last byte.
+        assert_eq!(parse_range("bytes=90-500", 100), Ok(Some((90, 99))));
+    }
+
+    #[test]
+    fn parses_suffix_and_open_ranges() {
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=50-", 100), Ok(Some((50, 99))));
+        assert_eq!(parse_range("bytes=0-", 100), Ok(Some((0, 99))));
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=100-200", 100), Err(()));
+        assert_eq!(parse_range("bytes=-0", 100), Err(()));
+        assert_eq!(parse_range("bytes=0-0", 0), Err(()));
+    }
+
+    #[test]
+    fn ignores_multiple_and_malformed_ranges() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+        assert_eq!(parse_range("bytes=5", 100), Ok(None));
+        assert_eq!(parse_range("bytes=-", 100), Ok(None));
+        assert_eq!(parse_range("bytes=9-3", 100), Ok(None));
+        assert_eq!(parse_range("bytes=a-3", 100), Ok(None));
+        assert_eq!(parse_range("bytes=-x", 100), Ok(None));
+    }
+
+    #[test]
+    fn maps_ranges_onto_parts() {
+        let layout = layout(3, 10);
+        assert_eq!(parts_for_range(&layout, None), 1..=3);
+        assert_eq!(parts_for_range(&layout, Some((0, 9))), 1..=1);
+        // Crossing the boundary between the first and second chunk.
+        assert_eq!(parts_for_range(&layout, Some((9, 10))), 1..=2);
+        assert_eq!(parts_for_range(&layout, Some((10, 19))), 2..=2);
+        assert_eq!(parts_for_range(&layout, Some((15, 25))), 2..=3);
+        // Never past the last part, even if the range ends in its padding.
+        assert_eq!(parts_for_range(&layout, Some((20, 35))), 3..=3);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::download::IntegrityError;
+use axum::{
+    extract::{multipart::MultipartError, rejection::{JsonRejection, QueryRejection}},
+    http::{
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+};
+use image::ImageError;
+use serde_json::json;
+use std::fmt;
+
+/// Everything that can go wrong while handling a request.
+/// Each variant maps onto one HTTP status and is sent to the client as
+/// `{"error": "<kind>", "message": "<details>"}`.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request itself is malformed (400).
+    BadRequest(String),
+    /// The request has no valid bearer token, and the endpoint needs one (401).
+    Unauthorized(String),
+    /// The signer isn't allowed to change the entity the request refers to (403).
+    Forbidden(String),
+    /// The entity the request refers to doesn't exist (404).
+    NotFound(String),
+    /// The upload is bigger than we accept (413).
+    PayloadTooLarge(String),
+    /// The upload isn't an image format we can decode (415).
+    UnsupportedMediaType(String),
+    /// The client hit the rate limit or its upload quota, and may retry after the given
+    /// number of seconds (429).
+    TooManyRequests(String, u64),
+    /// The stored chunks don't add up to the uploaded image (422).
+    Integrity(IntegrityError),
+    /// The Golem Base node failed or rejected an RPC call (502).
+    Upstream(String),
+    /// Anything else that went wrong on our side (500).
+    Internal(String),
+}
+
+impl AppError {
+    /// Wraps an error returned by a `GolemBaseClient` call. The node reports unknown
+    /// entity keys as an error whose message says "not found", which we turn into a 404.
+    pub fn rpc(e: impl fmt::Display) -> Self {
+        let message = e.to_string();
+        if message.to_lowercase().contains("not found") {
+            AppError::NotFound(message)
+        } else {
+            AppError::Upstream(message)
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Integrity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::UnsupportedMediaType(_) => "unsupported_media_type",
+            AppError::TooManyRequests(..) => "too_many_requests",
+            AppError::Integrity(_) => "integrity",
+            AppError::Upstream(_) => "upstream",
+            AppError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Integrity(e) => write!(f, "{}", e),
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::UnsupportedMediaType(message)
+            | AppError::TooManyRequests(message, _)
+            | AppError::Upstream(message)
+            | AppError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        eprintln!("Request failed ({}): {}", self.status(), self);
+        let mut body = json!({
+            "error": self.kind(),
+            "message": self.to_string(),
+        });
+        if let AppError::TooManyRequests(_, retry_after) = self {
+            body["retry_after"] = json!(retry_after);
+            return (self.status(), [(RETRY_AFTER, retry_after.to_string())], Json(body)).into_response();
+        }
+        if let AppError::Unauthorized(_) = self {
+            return (self.status(), [(WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response();
+        }
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(e.body_text())
+        } else {
+            AppError::BadRequest(e.body_text())
+        }
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(e.body_text())
+        } else {
+            AppError::BadRequest(e.body_text())
+        }
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(e: QueryRejection) -> Self {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+impl From<ImageError> for AppError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::Unsupported(_) | ImageError::Decoding(_) => {
+                AppError::UnsupportedMediaType(e.to_string())
+            }
+            ImageError::Limits(_) => AppError::PayloadTooLarge(e.to_string()),
+            _ => AppError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<IntegrityError> for AppError {
+    fn from(e: IntegrityError) -> Self {
+        AppError::Integrity(e)
+    }
+}
+
+/// The `upload` and `download` modules return boxed errors; pick the right variant
+/// for the ones we know about and treat the rest as RPC failures.
+impl From<Box<dyn std::error::Error + Send + Sync>> for AppError {
+    fn from(e: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        let e = match e.downcast::<IntegrityError>() {
+            Ok(integrity) => return AppError::Integrity(*integrity),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ImageError>() {
+            Ok(image) => return AppError::from(*image),
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(io) => AppError::from(*io),
+            Err(e) => AppError::rpc(e),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{
+    error::AppError,
+    query::Query,
+    signed_upload::{StorageChange, StorageLog},
+    AppState,
+};
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use golem_base_sdk::Hash;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Notify,
+};
+
+/// How many events a slow subscriber may fall behind before it is told to resync.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A change to the gallery, as sent to `GET /events` subscribers.
+#[derive(Clone, Debug, Serialize)]
+pub struct GalleryEvent {
+    /// `created`, `deleted` or `expired`.
+    pub kind: &'static str,
+    pub entity_key: String,
+    /// `image` or `thumbnail`.
+    pub entity_type: String,
+    /// For thumbnails, the image they belong to.
+    pub parent: Option<String>,
+    pub filename: Option<String>,
+    /// The block in which the change happened.
+    pub block: u64,
+}
+
+/// Fans the gallery events out to every subscriber.
+pub struct Events {
+    sender: broadcast::Sender<GalleryEvent>,
+    /// The earliest block at which a subscriber arrived since `watch` last looked, so it can
+    /// replay the logs from there once it has seeded its state.
+    subscribed_at: Mutex<Option<u64>>,
+    /// Wakes `watch` up when a subscriber arrives, so it doesn't wait for the next poll to seed.
+    subscribed: Notify,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            subscribed_at: Mutex::new(None),
+            subscribed: Notify::new(),
+        }
+    }
+}
+
+/// What we remember about an entity to describe it once it is gone.
+struct Known {
+    entity_type: String,
+    parent: Option<String>,
+    filename: Option<String>,
+    /// Kept up to date from the update and extension logs.
+    expires_at_block: u64,
+}
+
+/// The gallery entities being watched, and the first block whose logs haven't been read yet.
+struct Watched {
+    known: HashMap<Hash, Known>,
+    next_block: u64,
+    /// Entities that were already there when `known` was looked up, but whose creation may
+    /// still be in the logs being replayed (those since the first subscriber arrived).
+    unannounced: HashSet<Hash>,
+    /// The block up to which `unannounced` matters.
+    seeded_at: u64,
+}
+
+/// Watches the images and thumbnails with our `app` annotation and broadcasts their creation,
+/// deletion and expiry. When the first subscriber arrives the current entities are looked up
+/// once; from then on, every `events_poll_interval` only the storage logs of the new blocks are
+/// read. Expired entities are removed with a deletion log too, so a deletion at or after an
+/// entity's (last known) expiry block counts as its expiry.
+/// Nothing is polled while nobody is subscribed.
+pub async fn watch(state: Arc<AppState>) {
+    let interval = Duration::from_secs(state.config.events_poll_interval);
+    let mut watched: Option<Watched> = None;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = state.events.subscribed.notified(), if watched.is_none() => {}
+        }
+        let subscribed_at = state.events.subscribed_at.lock().unwrap().take();
+        if state.events.sender.receiver_count() == 0 {
+            watched = None;
+            continue;
+        }
+        let result = match watched.as_mut() {
+            Some(watched) => poll(&state, watched).await,
+            None => seed(&state, subscribed_at).await.map(|seeded| watched = Some(seeded)),
+        };
+        if let Err(e) = result {
+            eprintln!("Error polling for gallery events: {}", e);
+        }
+    }
+}
+
+/// Looks up the gallery entities that exist right now. The logs are then read from
+/// `subscribed_at`, the block at which the first subscriber arrived, so whatever changed while
+/// this was being looked up is still sent. Without it, they are read from the lookup's block.
+async fn seed(state: &AppState, subscribed_at: Option<u64>) -> Result<Watched, AppError> {
+    let query = Query::eq("app", state.config.app_tag.as_str())
+        .and(Query::eq("type", "image").or(Query::eq("type", "thumbnail")));
+    let block = state.client.get_current_block_number().await.map_err(AppError::rpc)?;
+    let keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+    let known: HashMap<Hash, Known> = describe(state, keys).await?.into_iter().flatten().collect();
+    println!("Watching {} entities for gallery events", known.len());
+    Ok(Watched {
+        unannounced: known.keys().copied().collect(),
+        known,
+        next_block: subscribed_at.unwrap_or(block).min(block),
+        seeded_at: block,
+    })
+}
+
+/// Reads the storage logs since the last poll and broadcasts the changes to gallery entities.
+async fn poll(state: &AppState, watched: &mut Watched) -> Result<(), AppError> {
+    let block = state.client.get_current_block_number().await.map_err(AppError::rpc)?;
+    if block < watched.next_block {
+        return Ok(());
+    }
+    let logs = state.relay.storage_logs(watched.next_block, block).await?;
+
+    // An image that was uploaded in several chunks only becomes one with its final update,
+    // so new entities are looked for among the updated ones too.
+    let mut candidates: Vec<Hash> = logs
+        .iter()
+        .filter(|log| matches!(log.change, StorageChange::Created | StorageChange::Updated))
+        .map(|log| log.entity_key)
+        .filter(|key| !watched.known.contains_key(key))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    let mut found: HashMap<Hash, Known> = describe(state, candidates).await?.into_iter().flatten().collect();
+
+    for log in logs {
+        apply(state, watched, &mut found, log);
+    }
+    watched.next_block = block + 1;
+    if watched.next_block > watched.seeded_at {
+        watched.unannounced.clear();
+    }
+    Ok(())
+}
+
+/// Updates the watched entities with one storage log and broadcasts what it means for the
+/// gallery. `found` holds the gallery entities that were created since the last poll.
+fn apply(state: &AppState, watched: &mut Watched, found: &mut HashMap<Hash, Known>, log: StorageLog) {
+    let Watched { known, unannounced, .. } = watched;
+    let key = log.entity_key;
+    match log.change {
+        StorageChange::Created | StorageChange::Updated | StorageChange::Extended => {
+            if let Some(entity) = known.get_mut(&key) {
+                entity.expires_at_block = log.expires_at_block.unwrap_or(entity.expires_at_block);
+                // Created after the first subscriber arrived, but before we looked.
+                if log.change == StorageChange::Created && unannounced.remove(&key) {
+                    state.events.send(event("created", key, entity, log.block));
+                }
+            } else if log.change != StorageChange::Extended && let Some(mut entity) = found.remove(&key) {
+                entity.expires_at_block = log.expires_at_block.unwrap_or(entity.expires_at_block);
+                state.events.send(event("created", key, &entity, log.block));
+                known.insert(key, entity);
+            }
+        }
+        StorageChange::Deleted => {
+            unannounced.remove(&key);
+            if let Some(entity) = known.remove(&key) {
+                let kind = if entity.expires_at_block <= log.block { "expired" } else { "deleted" };
+                state.events.send(event(kind, key, &entity, log.block));
+            }
+        }
+    }
+}
+
+/// Fetches what we need to know about `keys` from their metadata, skipping the ones that
+/// aren't our images or thumbnails, or are already gone again.
+async fn describe(state: &AppState, keys: Vec<Hash>) -> Result<Vec<Option<(Hash, Known)>>, AppError> {
+    stream::iter(keys)
+        .map(|key| async move {
+            let metadata = match state.client.get_entity_metadata(key).await.map_err(AppError::rpc) {
+                Ok(metadata) => metadata,
+                Err(AppError::NotFound(_)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let annotation = |name: &str| {
+                metadata
+                    .string_annotations
+                    .iter()
+                    .find(|annot| annot.key == name)
+                    .map(|annot| annot.value.clone())
+            };
+            let entity_type = annotation("type").unwrap_or_default();
+            if annotation("app").as_deref() != Some(state.config.app_tag.as_str())
+                || !matches!(entity_type.as_str(), "image" | "thumbnail")
+            {
+                return Ok(None);
+            }
+            let entity = Known {
+                entity_type,
+                parent: annotation("parent"),
+                filename: annotation("filename"),
+                expires_at_block: metadata.expires_at_block,
+            };
+            Ok::<_, AppError>(Some((key, entity)))
+        })
+        .buffered(state.config.chunk_fetch_concurrency)
+        .try_collect()
+        .await
+}
+
+fn event(kind: &'static str, key: Hash, entity: &Known, block: u64) -> GalleryEvent {
+    GalleryEvent {
+        kind,
+        entity_key: key.to_string(),
+        entity_type: entity.entity_type.clone(),
+        parent: entity.parent.clone(),
+        filename: entity.filename.clone(),
+        block,
+    }
+}
+
+impl Events {
+    fn send(&self, event: GalleryEvent) {
+        println!("Gallery event: {} {} {}", event.kind, event.entity_type, event.entity_key);
+        // Only fails when nobody is subscribed, which is fine.
+        let _ = self.sender.send(event);
+    }
+}
+
+// Handler for the `GET /events` route.
+// A server-sent event stream of gallery changes. Every event is named after its kind
+// (`created`, `deleted` or `expired`) and carries a `GalleryEvent` as JSON. A subscriber that
+// falls too far behind gets a `resync` event and should reload the gallery.
+// Like every read, it needs a bearer token when `public_reads` is off. Browsers' `EventSource`
+// can't send one, so the live updates of the upload form only work with public reads.
+pub async fn gallery_events(State(state): State<Arc<AppState>>) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, AppError> {
+    println!("GET /events called.");
+    let block = state.client.get_current_block_number().await.map_err(AppError::rpc)?;
+    let receiver = state.events.sender.subscribe();
+    state.events.subscribed_at.lock().unwrap().get_or_insert(block);
+    state.events.subscribed.notify_one();
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => Event::default().event(event.kind).json_data(&event),
+            Err(RecvError::Lagged(missed)) => Ok(Event::default().event("resync").data(missed.to_string())),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
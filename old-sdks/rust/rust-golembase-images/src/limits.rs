@@ -0,0 +1,228 @@
+use crate::{config::Config, error::AppError, rendition::Rendition, AppState};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Once this many clients have a bucket, the ones that have refilled completely are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A token bucket per client IP. Each bucket holds up to `burst` requests and refills at
+/// `per_minute` requests per minute.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, per_second: f64, burst: f64) -> f64 {
+        (self.tokens + now.duration_since(self.updated).as_secs_f64() * per_second).min(burst)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        RateLimiter {
+            per_second: per_minute as f64 / 60.0,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket, or returns the number of seconds until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.refilled(now, self.per_second, self.burst) < self.burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, self.per_second, self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.per_second).ceil() as u64)
+        }
+    }
+}
+
+/// The number of bytes each client IP has uploaded today (UTC). Only kept in memory, so
+/// it starts over when the server restarts.
+pub struct DailyQuota {
+    limit: u64,
+    usage: Mutex<(u64, HashMap<IpAddr, u64>)>,
+}
+
+impl DailyQuota {
+    /// A quota of `limit` bytes per day; 0 means no limit.
+    pub fn new(limit: u64) -> Self {
+        DailyQuota {
+            limit,
+            usage: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Counts `bytes` more against `ip`'s quota, or refuses them if that would go over it.
+    pub fn charge(&self, ip: IpAddr, bytes: usize) -> Result<(), AppError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.charge_at(ip, bytes, now)
+    }
+
+    /// `charge` at `now`, in seconds since the Unix epoch.
+    fn charge_at(&self, ip: IpAddr, bytes: usize, now: u64) -> Result<(), AppError> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+        let today = now / SECONDS_PER_DAY;
+
+        let mut usage = self.usage.lock().unwrap();
+        let (day, used_by) = &mut *usage;
+        if *day != today {
+            *day = today;
+            used_by.clear();
+        }
+        let used = used_by.entry(ip).or_default();
+        if *used + bytes as u64 > self.limit {
+            return Err(AppError::TooManyRequests(
+                format!("Daily upload quota of {} bytes used up ({} bytes uploaded today).", self.limit, used),
+                (today + 1) * SECONDS_PER_DAY - now,
+            ));
+        }
+        *used += bytes as u64;
+        Ok(())
+    }
+}
+
+/// Refuses an upload that has grown past `max_upload_size`, or past the number of chunks it
+/// may be split into.
+pub fn check_upload_size(config: &Config, len: usize) -> Result<(), AppError> {
+    if len > config.max_upload_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "The upload is larger than the maximum of {} bytes.",
+            config.max_upload_size
+        )));
+    }
+    if len.div_ceil(config.chunk_size) as u64 > config.max_chunks {
+        return Err(AppError::PayloadTooLarge(format!(
+            "The upload would need more than the maximum of {} chunks of {} bytes.",
+            config.max_chunks, config.chunk_size
+        )));
+    }
+    Ok(())
+}
+
+/// Refuses a rendition that doesn't fit into one entity of `chunk_size` bytes, the limit every
+/// other entity we store is kept under.
+pub fn check_rendition_size(config: &Config, rendition: &Rendition, len: usize) -> Result<(), AppError> {
+    if len > config.chunk_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "The {} rendition is {} bytes, more than the chunk size of {} bytes; use a smaller size, a lossy format or a lower quality.",
+            rendition, len, config.chunk_size
+        )));
+    }
+    Ok(())
+}
+
+/// Middleware that applies `AppState::rate_limiter` to every request, keyed by the client's IP.
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if let Err(retry_after) = state.rate_limiter.check(addr.ip()) {
+        return Err(AppError::TooManyRequests(
+            format!("Too many requests from {}, slow down.", addr.ip()),
+            retry_after,
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn rate_limiter_allows_a_burst_then_refills() {
+        let limiter = RateLimiter::new(60, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(CLIENT, start), Ok(()));
+        }
+        // One request per second: the next token is a second away.
+        assert_eq!(limiter.check_at(CLIENT, start), Err(1));
+        assert_eq!(limiter.check_at(OTHER, start), Ok(()));
+
+        assert_eq!(limiter.check_at(CLIENT, start + Duration::from_millis(1500)), Ok(()));
+        assert!(limiter.check_at(CLIENT, start + Duration::from_millis(1500)).is_err());
+
+        // A long pause refills the bucket, but never beyond the burst.
+        let later = start + Duration::from_secs(600);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(CLIENT, later), Ok(()));
+        }
+        assert!(limiter.check_at(CLIENT, later).is_err());
+    }
+
+    #[test]
+    fn rate_limiter_rounds_the_retry_delay_up() {
+        let limiter = RateLimiter::new(6, 1);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(CLIENT, start), Ok(()));
+        assert_eq!(limiter.check_at(CLIENT, start), Err(10));
+        assert_eq!(limiter.check_at(CLIENT, start + Duration::from_millis(2500)), Err(8));
+    }
+
+    #[test]
+    fn quota_counts_per_client_and_resets_at_midnight() {
+        let quota = DailyQuota::new(100);
+        let morning = 20_000 * SECONDS_PER_DAY + 8 * 3600;
+        assert!(quota.charge_at(CLIENT, 60, morning).is_ok());
+        assert!(quota.charge_at(CLIENT, 40, morning + 1).is_ok());
+        match quota.charge_at(CLIENT, 1, morning + 2) {
+            Err(AppError::TooManyRequests(_, retry_after)) => assert_eq!(retry_after, 16 * 3600 - 2),
+            other => panic!("expected the quota to be used up, got {:?}", other),
+        }
+        assert!(quota.charge_at(OTHER, 100, morning).is_ok());
+
+        // A refused upload doesn't count, and the next day starts from zero.
+        let tomorrow = 20_001 * SECONDS_PER_DAY;
+        assert!(quota.charge_at(CLIENT, 100, tomorrow).is_ok());
+        assert!(quota.charge_at(CLIENT, 1, tomorrow).is_err());
+    }
+
+    #[test]
+    fn zero_quota_means_no_limit() {
+        let quota = DailyQuota::new(0);
+        assert!(quota.charge_at(CLIENT, usize::MAX, 0).is_ok());
+    }
+}
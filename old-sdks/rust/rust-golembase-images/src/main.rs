@@ -1,65 +1,209 @@
 use axum::{
-    extract::{multipart::Multipart, Path, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Json},
+    body::Body,
+    extract::{multipart::{Field, Multipart}, rejection::{JsonRejection, QueryRejection}, ConnectInfo, DefaultBodyLimit, FromRequest, Path, Request, State},
+    http::{
+        header::{CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE},
+        HeaderMap, StatusCode,
+    },
+    middleware,
+    response::{Html, IntoResponse, Json, Response},
     routing::{get, post},
-    Router,
+    Extension, Form, Router,
 };
 use golem_base_sdk::{
-    entity::{Annotation, Create},
-    GolemBaseClient, PrivateKeySigner, Url,
+    entity::{Annotation, Create, Extend, Update},
+    GolemBaseClient, PrivateKeySigner,
 };
-use bytes::Bytes;
 use golem_base_sdk::Hash;
-use alloy_primitives::B256;
+use alloy_primitives::{keccak256, Address, B256};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use hex::FromHex;
-use image::{imageops::FilterType, ImageFormat};
+use image::DynamicImage;
+use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::json;
-use std::{/*fs,*/ sync::Arc};
+use std::{
+    /*fs,*/ collections::{BTreeMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
-use std::io::{Cursor };
-use dirs::config_dir;
+use auth::{Auth, Identity};
+use config::{Command, Config};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use bytes::Bytes;
+use download::{
+    chunk_keys, fetch_chunks, parse_range, parts_for_range, sha256_hex, stream_image, ImageLayout,
+    IntegrityError,
+};
+use error::AppError;
+use events::{gallery_events, Events};
+use futures::{future::try_join_all, stream, StreamExt, TryStreamExt};
+use limits::{check_rendition_size, check_upload_size, DailyQuota, RateLimiter};
+use metadata::{read_exif, strip_metadata};
+use query::Query;
+use search::{paginate, SearchParams};
+use tags::{check_tags, normalize_tag, normalize_tags, stored_tags, tag_annotations};
+use rendition::{render, Encoding, FitMode, OutputFormat, Rendered, Rendition};
+use signed_upload::{
+    check_signed, encode_transaction, unsigned_transaction, PendingUpload, PendingUploads, Relay, Stage,
+};
+use upload::{
+    add_layout_annotations, chunk_create, decode_upload, provisional_main_create, sniff_mime_type,
+    ChunkedUpload, FinishedUpload, Layout, SNIFF_LEN,
+};
+
+mod auth;
+mod config;
+mod download;
+mod error;
+mod events;
+mod limits;
+mod metadata;
+mod migrate;
+mod query;
+mod rendition;
+mod search;
+mod signed_upload;
+mod tags;
+mod upload;
 
 /// We'll use this struct to hold our shared state, including the GolemBase client.
-/// For now it only has one member but this way we can add additional state later if necessary.
 struct AppState {
     client: GolemBaseClient,
+    config: Config,
+    rate_limiter: RateLimiter,
+    upload_quota: DailyQuota,
+    auth: Auth,
+    relay: Relay,
+    pending_uploads: PendingUploads,
+    events: Events,
+}
+
+/// The body of a `POST /add-resize/:id` request, sent either as a form or as JSON.
+/// If only one dimension is given, the other is derived from the original aspect ratio.
+/// `format` (jpeg, png, webp or avif) defaults to jpeg; `quality` (1-100) is only accepted for jpeg and avif.
+#[derive(Deserialize)]
+struct ResizeRequest {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+/// The body of a `POST /image/:id/extend` request, sent either as a form or as JSON.
+#[derive(Deserialize)]
+struct ExtendRequest {
+    blocks: u64,
 }
 
+/// The JSON body of a `PATCH /image/:id` request. Fields that are left out stay unchanged.
+/// `tags` is a comma-separated list that replaces all tags.
+/// `annotations` sets custom annotations; a `null` value removes one.
+#[derive(Deserialize)]
+struct PatchRequest {
+    tags: Option<String>,
+    filename: Option<String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, Option<String>>,
+}
+
+/// Annotations that describe how an image is stored. They can't be changed through `PATCH /image/:id`.
+const RESERVED_ANNOTATIONS: &[&str] = &[
+    "type", "app", "parent", "mime_type", "sha256", "chunk_sha256", "tag", "filename",
+    "camera_make", "camera_model", "captured_at", "uploader",
+];
+
+/// The JSON body of a `POST /signed-uploads/:id` request: the prepared transactions, signed
+/// and hex encoded, in the order they were handed out.
+#[derive(Deserialize)]
+struct SignedTransactions {
+    signed_transactions: Vec<String>,
+}
+
+/// The query string of a `GET /thumbnails` request.
+/// With `data=true` every thumbnail's image is included as base64.
+#[derive(Deserialize)]
+struct ThumbnailsParams {
+    #[serde(default)]
+    data: bool,
+}
+
+/// The query string of a `GET /image/:id/thumbnail` request.
+#[derive(Deserialize)]
+struct ThumbnailParams {
+    size: Option<String>,
+}
+
+/// A resized copy of an image that was just stored as a thumbnail entity.
+struct StoredResize {
+    entity_key: Hash,
+    data: Bytes,
+    resize: String,
+    mime_type: String,
+    expires_at_block: u64,
+}
+
+/// Room on top of `max_upload_size` for the other fields of the upload form and the multipart framing.
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+/// Room in a `POST /signed-uploads/:id` body for each transaction's signature, fees, annotations and JSON quoting.
+const SIGNED_TRANSACTION_OVERHEAD: usize = 4 * 1024;
+
+/// How long (in seconds) clients may cache a thumbnail served by `GET /image/:id/thumbnail`.
+/// Thumbnails don't change, but they can expire or be deleted together with their image.
+const THUMBNAIL_MAX_AGE: u64 = 3600;
+
 pub struct ImageResult {
     pub id: Hash,
     pub image_data: Vec<u8>, // Using Vec<u8> since Bytes will be created at the end for the response
     pub filename: String,
     pub mimetype: String,
+    pub tags: Vec<String>,
+    pub expires_at_block: u64,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("A");
     dotenvy::dotenv().ok();
 
-    println!("B");
+    // Settings come from CLI flags, then environment variables, then config.toml.
+    // The wallet password is a secret, so it is only ever read from the environment.
+    let config = Config::load()?;
+    if let Some(Command::IssueToken { identity, valid_for }) = &config.command {
+        let secret = config
+            .auth_hmac_secret
+            .as_deref()
+            .ok_or("issue-token needs AUTH_HMAC_SECRET to be set")?;
+        println!("{}", auth::issue_token(secret, identity, *valid_for)?);
+        return Ok(());
+    }
     let password = std::env::var("GOLEMDB_PASS")?;
 
-    println!("C");
-    let keypath = config_dir()
-        .ok_or("Failed to get config directory")?
-        .join("golembase")
-        .join("wallet.json");
-    let signer = PrivateKeySigner::decrypt_keystore(keypath, password.trim_end())?;
+    println!("Loading keystore from {:?}", config.keystore_path);
+    let signer = PrivateKeySigner::decrypt_keystore(config.keystore_path.clone(), password.trim_end())?;
 
-    let rpc_url_str = "http://localhost:8545";
-    let rpc_url = Url::parse(rpc_url_str).unwrap();
+    println!("Connecting to {}", config.rpc_url);
+    let bind_address = config.bind_address;
 
     // The GolemBaseClient is now part of our application's shared state.
     // It's wrapped in an Arc for thread-safe access from multiple request handlers.
-    println!("Here");
     let shared_state = Arc::new(AppState {
         client: GolemBaseClient::builder()
             .wallet(signer.clone())
-            .rpc_url(rpc_url.clone())
+            .rpc_url(config.rpc_url.clone())
             .build(),
+        rate_limiter: RateLimiter::new(config.rate_limit_per_minute, config.rate_limit_burst),
+        upload_quota: DailyQuota::new(config.daily_upload_quota),
+        auth: Auth {
+            api_keys: config.api_keys.clone(),
+            hmac_secret: config.auth_hmac_secret.clone(),
+        },
+        relay: Relay::new(config.rpc_url.clone()),
+        pending_uploads: PendingUploads::default(),
+        events: Events::default(),
+        config,
     });
 
     println!(
@@ -67,111 +211,318 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         signer.address()
     );
 
+    if let Some(Command::MigrateTags { dry_run }) = shared_state.config.command {
+        return migrate::migrate_tags(&shared_state, dry_run).await;
+    }
+    if !shared_state.auth.is_enabled() {
+        println!("Warning: no API keys or AUTH_HMAC_SECRET configured, anyone can upload and change images.");
+    }
+
+    // Watch for new, deleted and expired images in the background and push them to `/events`.
+    tokio::spawn(events::watch(shared_state.clone()));
+
     // Set up the Axum router and routes.
+    let upload_body_limit = shared_state.config.max_upload_size + MULTIPART_OVERHEAD;
+    let signed_body_limit = signed_transactions_body_limit(&shared_state.config);
     let app = Router::new()
         // The "/" route serves the HTML form, replicating the TS app's front end.
         .route("/", get(serve_html))
         // The "/upload" route handles the image upload.
-        .route("/upload", post(upload_handler))
-        // The "/thumbnails" route. Note: This handler is a placeholder.
+        .route("/upload", post(upload_handler).layer(DefaultBodyLimit::max(upload_body_limit)))
+        // The "/signed-uploads" routes let uploaders sign the transactions with their own wallet.
+        .route("/signed-uploads", post(prepare_signed_upload).layer(DefaultBodyLimit::max(upload_body_limit)))
+        .route("/signed-uploads/:id", post(submit_signed_upload).layer(DefaultBodyLimit::max(signed_body_limit)))
+        // The "/thumbnails" route lists thumbnails with their metadata.
         .route("/thumbnails", get(get_thumbnails))
-        // The "/parent/:thumbid" route. Note: This handler is a placeholder.
+        // The "/parent/:thumbid" route returns the key of the image a thumbnail belongs to.
         .route("/parent/:thumbid", get(get_parent))
-        // The "/image/:id" route. Note: This handler is a placeholder.
-        .route("/image/:id", get(get_full_image))
-        // The "/add-resize/:id" route. Note: This handler is a placeholder.
+        // The "/image/:id" route streams an image, updates its annotations, or deletes it with everything belonging to it.
+        .route("/image/:id", get(get_full_image).patch(patch_image).delete(delete_image))
+        // The "/image/:id/extend" route keeps an image and everything belonging to it alive for longer.
+        .route("/image/:id/extend", post(extend_image))
+        // The "/image/:id/thumbnail" route serves a thumbnail of the requested size, creating it if needed.
+        .route("/image/:id/thumbnail", get(get_thumbnail))
+        // The "/add-resize/:id" route stores a resized copy of an image.
         .route("/add-resize/:id", post(add_resize))
-        // The "/query/:search" route. Note: This handler is a placeholder.
+        // The "/query/:search" route lists the gallery thumbnails of the images with exactly that tag.
         .route("/query/:search", get(query_entities))
+        // The "/search" route combines filters over tags, filename, type, upload time, size and camera metadata.
+        .route("/search", get(search_images))
+        // The "/owners/:address/images" and "/me/images" routes are the galleries of one wallet or one uploader.
+        .route("/owners/:address/images", get(owner_images))
+        .route("/me/images", get(my_images))
+        // The "/events" route pushes new, deleted and expired images to browsers as server-sent events.
+        // It is a read like the others, so it needs a token when `public_reads` is off.
+        .route("/events", get(gallery_events))
+        // Requests that change something need a bearer token once credentials are configured.
+        .route_layer(middleware::from_fn_with_state(shared_state.clone(), auth::authenticate))
+        // Every client IP is rate limited, across all routes.
+        .route_layer(middleware::from_fn_with_state(shared_state.clone(), limits::rate_limit))
         // We add our state to the router so it's available to all handlers.
         .with_state(shared_state)
         // Add a CORS layer for development to allow cross-origin requests from a frontend.
         .layer(CorsLayer::permissive());
 
     // Start the server.
-    let listener = TcpListener::bind("127.0.0.1:3000").await?;
-    println!("listening on http://127.0.0.1:3000");
-    axum::serve(listener, app).await?;
+    let listener = TcpListener::bind(bind_address).await?;
+    println!("listening on http://{}", bind_address);
+    // The rate limiter and upload quota need the client's address.
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
 /// Helper function for converting string entity_key to hex
-
-fn parse_b256(thumbid: &str) -> B256 {
+fn parse_b256(thumbid: &str) -> Result<B256, AppError> {
     let hex_str = thumbid.strip_prefix("0x").unwrap_or(thumbid);
     let bytes: [u8; 32] = <[u8; 32]>::from_hex(hex_str)
-        .expect("Invalid hex string for B256");
-    B256::from(bytes)
+        .map_err(|_| AppError::BadRequest(format!("Invalid entity key: {}", thumbid)))?;
+    Ok(B256::from(bytes))
 }
 
-/// Helper function to retrieve all image data and combine chunks.
-async fn get_full_image_data(
-    client: &GolemBaseClient,
-    id: Hash,
-) -> Result<ImageResult, Box<dyn std::error::Error>> {
-    let metadata = client.get_entity_metadata(id).await?;
-    
-    // --- 1. EXTRACT METADATA ---
-    let mut filename = "image".to_string();
-    let mut mime_type = "application/octet-stream".to_string();
-    let mut part_of: u64 = 1;
-
-    for annot in metadata.string_annotations {
-        if annot.key == "filename" {
-            filename = annot.value;
-        } else if annot.key == "mime-type" {
-            mime_type = annot.value;
-        }
+/// Helper function that parses the optional `btl` upload field, falling back to the configured
+/// default and refusing lifetimes longer than `max_btl`.
+fn parse_btl(value: &str, config: &Config) -> Result<u64, AppError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(config.btl);
     }
-    for annot in metadata.numeric_annotations {
-        if annot.key == "part-of" {
-            part_of = annot.value;
+    match value.parse::<u64>() {
+        Ok(btl) if btl > 0 && btl <= config.max_btl => Ok(btl),
+        _ => Err(AppError::BadRequest(format!(
+            "btl must be a number of blocks between 1 and {}, got {:?}.",
+            config.max_btl, value
+        ))),
+    }
+}
+
+/// The fields of an upload form other than the image file, shared by `/upload` and
+/// `/signed-uploads`. `btl` and `strip_metadata` decide how the image is stored, so they are
+/// refused once `imageFile` has been seen.
+struct UploadForm {
+    /// Only used by `/signed-uploads`, which parses it.
+    owner: Option<String>,
+    filename: Option<String>,
+    tags: Option<String>,
+    custom_annotations: Vec<Annotation<String>>,
+    btl: u64,
+    strip: bool,
+    has_image: bool,
+    /// The `custom_keyN` and `custom_valueN` fields, by their `N`.
+    custom_fields: BTreeMap<String, (Option<String>, Option<String>)>,
+}
+
+impl UploadForm {
+    fn new(config: &Config) -> Self {
+        UploadForm {
+            owner: None,
+            filename: None,
+            tags: None,
+            custom_annotations: vec![],
+            btl: config.btl,
+            strip: false,
+            has_image: false,
+            custom_fields: BTreeMap::new(),
         }
     }
 
-    println!("Fetching raw data for {} (MIME: {})", filename, mime_type);
+    /// Reads one field of the form. The `imageFile` field is handed back for the caller to consume.
+    async fn read<'f>(&mut self, config: &Config, field: Field<'f>) -> Result<Option<Field<'f>>, AppError> {
+        let name = field
+            .name()
+            .ok_or_else(|| AppError::BadRequest("Multipart field without a name.".to_string()))?
+            .to_string();
 
-    // --- 2. FETCH FIRST CHUNK (Storage Value) ---
-    // The main entity hash contains the first chunk of data.
-    //let first_chunk = client.get_storage_value(id).await?.to_vec();
-    let first_chunk = client.get_storage_value::<Vec<u8>>(id).await?.to_vec();
-    let mut all_chunks = vec![first_chunk];
+        match name.as_str() {
+            "owner" => self.owner = Some(field.text().await?),
+            "filename" => self.filename = Some(field.text().await?),
+            "tags" => self.tags = Some(field.text().await?),
+            "btl" => {
+                if self.has_image {
+                    return Err(AppError::BadRequest("The btl field must come before imageFile.".to_string()));
+                }
+                self.btl = parse_btl(&field.text().await?, config)?;
+            }
+            "strip_metadata" => {
+                if self.has_image {
+                    return Err(AppError::BadRequest("The strip_metadata field must come before imageFile.".to_string()));
+                }
+                self.strip = matches!(field.text().await?.as_str(), "on" | "true" | "1");
+            }
+            "imageFile" => {
+                if self.has_image {
+                    return Err(AppError::BadRequest("Only one imageFile can be uploaded at a time.".to_string()));
+                }
+                self.has_image = true;
+                return Ok(Some(field));
+            }
+            custom_key if custom_key.starts_with("custom_key") => {
+                let pair = self.custom_fields.entry(custom_key["custom_key".len()..].to_string()).or_default();
+                pair.0 = Some(field.text().await?);
+            }
+            custom_value if custom_value.starts_with("custom_value") => {
+                let pair = self.custom_fields.entry(custom_value["custom_value".len()..].to_string()).or_default();
+                pair.1 = Some(field.text().await?);
+            }
+            _ => {
+                // Ignore other fields
+            }
+        }
+        Ok(None)
+    }
 
-    // --- 3. FETCH REMAINING CHUNKS IF NECESSARY ---
-    if part_of > 1 {
-        for i in 2..=(part_of as usize) {
-            // Build the query to find the next chunk entity key
-            let query = format!(
-                "parent=\"{}\" && type=\"image_chunk\" && app=\"golem-images-0.1\" && part={}",
-                id, i
-            );
-            println!("Querying for chunk {}: {}", i, query);
-
-            let chunk_info = client.query_entities(&query).await?;
-            
-            if let Some(chunk_entity) = chunk_info.first() {
-                // Fetch the storage value (the chunk data) using the found entity key
-                //let chunk_data = client.get_storage_value(chunk_entity.entity_key).await?.to_vec();
-                let chunk_data = client.get_storage_value::<Vec<u8>>(chunk_entity.key).await?.to_vec();
-                all_chunks.push(chunk_data);
-            } else {
-                eprintln!("Warning: Expected chunk {} not found.", i);
-                // In a real app, you might return an error here, but we'll continue.
+    /// Pairs every `custom_keyN` with its `custom_valueN` into `custom_annotations`. Pairs with
+    /// an empty value are skipped, like the empty rows of the upload form.
+    fn finish(&mut self) -> Result<(), AppError> {
+        for (n, (key, value)) in std::mem::take(&mut self.custom_fields) {
+            let key = key.unwrap_or_default().trim().to_string();
+            let value = value.unwrap_or_default();
+            if value.is_empty() {
+                continue;
             }
+            if key.is_empty() {
+                return Err(AppError::BadRequest(format!("custom_value{} has no matching custom_key{}.", n, n)));
+            }
+            if RESERVED_ANNOTATIONS.contains(&key.as_str()) {
+                return Err(AppError::BadRequest(format!("Annotation {:?} can't be set by the uploader.", key)));
+            }
+            self.custom_annotations.push(Annotation::new(key, value));
         }
-        println!("Combined {} chunks.", all_chunks.len());
+        Ok(())
+    }
+}
+
+/// Helper function that fails with a 403 unless our signer owns `entity_key`.
+fn ensure_owner(state: &AppState, entity_key: Hash, owner: Address) -> Result<(), AppError> {
+    let signer = state.client.get_owner_address();
+    if owner != signer {
+        return Err(AppError::Forbidden(format!(
+            "Image {} is owned by {}, not by {}.",
+            entity_key, owner, signer
+        )));
     }
+    Ok(())
+}
+
+/// Helper function that fails with a 403 unless the caller's `identity` is the `uploader` of the
+/// entity with these annotations. Everything uploaded through `/upload` is owned by our signer,
+/// so once authentication is on the owner check alone would let any valid token change any
+/// image. Images stored without an `uploader` can't be changed through the API then.
+fn ensure_uploader(
+    state: &AppState,
+    entity_key: Hash,
+    annotations: &[Annotation<String>],
+    identity: Option<&Identity>,
+) -> Result<(), AppError> {
+    if !state.auth.is_enabled() {
+        return Ok(());
+    }
+    let identity = identity.ok_or_else(|| AppError::Unauthorized("This endpoint needs an Authorization: Bearer token.".to_string()))?;
+    let uploader = annotations.iter().find(|annot| annot.key == "uploader").map(|annot| annot.value.as_str());
+    if uploader != Some(identity.0.as_str()) {
+        return Err(AppError::Forbidden(format!("Image {} wasn't uploaded by {}.", entity_key, identity.0)));
+    }
+    Ok(())
+}
+
+/// Helper function that fails with a 400 unless the entity with these annotations is an image,
+/// rather than one of its chunks or thumbnails.
+fn ensure_image(entity_key: Hash, annotations: &[Annotation<String>]) -> Result<(), AppError> {
+    if !annotations.iter().any(|annot| annot.key == "type" && annot.value == "image") {
+        return Err(AppError::BadRequest(format!("{} is not an image.", entity_key)));
+    }
+    Ok(())
+}
+
+/// Helper function that finds the entities pointing at `parent` (optionally only those of
+/// `type=kind`) that `owner`, the owner of `parent`, owns too.
+/// Anyone can create an entity claiming one of our images as its parent; those aren't part
+/// of the image, and including them would make us change or pay for other people's entities.
+async fn owned_children(state: &AppState, parent: Hash, owner: Address, kind: Option<&str>) -> Result<Vec<Hash>, AppError> {
+    let mut query = Query::eq("parent", parent.to_string()).and(Query::eq("app", state.config.app_tag.as_str()));
+    if let Some(kind) = kind {
+        query = query.and(Query::eq("type", kind));
+    }
+    println!("Looking up children of {}: {}", parent, query);
+    let children = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+
+    let owned: Vec<Option<Hash>> = stream::iter(children)
+        .map(|key| async move {
+            let metadata = state.client.get_entity_metadata(key).await.map_err(AppError::rpc)?;
+            Ok::<_, AppError>((metadata.owner == owner).then_some(key))
+        })
+        .buffer_unordered(state.config.chunk_fetch_concurrency)
+        .try_collect()
+        .await?;
+    Ok(owned.into_iter().flatten().collect())
+}
 
-    // --- 4. COMBINE AND RETURN ---
-    // Flatten the vector of Vec<u8> chunks into a single Vec<u8>.
-    let image_data = all_chunks.into_iter().flatten().collect::<Vec<u8>>();
+/// Helper function that finds the `resize` thumbnail of `parent`. Like chunks, thumbnails can be
+/// attached to any image by anyone, so only those owned by `owner` (the owner of `parent`) are
+/// considered.
+/// Two requests racing for a missing size can both store it; always picking the lowest key
+/// keeps the answer (and the ETag) stable afterwards.
+async fn find_thumbnail(state: &AppState, parent: Hash, owner: Address, resize: &str) -> Result<Option<Hash>, AppError> {
+    let query = Query::eq("parent", parent.to_string())
+        .and(Query::eq("type", "thumbnail"))
+        .and(Query::eq("resize", resize))
+        .and(Query::eq("app", state.config.app_tag.as_str()));
+    println!("Looking up the {} thumbnail of {}: {}", resize, parent, query);
+    let candidates = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+
+    let trusted: Vec<Option<Hash>> = stream::iter(candidates)
+        .map(|key| async move {
+            let metadata = state.client.get_entity_metadata(key).await.map_err(AppError::rpc)?;
+            Ok::<_, AppError>((metadata.owner == owner).then_some(key))
+        })
+        .buffer_unordered(state.config.chunk_fetch_concurrency)
+        .try_collect()
+        .await?;
+    Ok(trusted.into_iter().flatten().min())
+}
+
+/// Helper function that checks an `If-None-Match` header against `etag`. The header is a
+/// comma-separated list of entity tags, weak ones (`W/"..."`) included, or `*`.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.trim() == "*"
+        || header
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Helper function that works out the `btl` to pass to an update so the entity keeps its current expiry.
+async fn remaining_btl(state: &AppState, expires_at_block: u64) -> Result<u64, AppError> {
+    let current_block = state.client.get_current_block_number().await.map_err(AppError::rpc)?;
+    Ok(expires_at_block.saturating_sub(current_block).max(1))
+}
+
+/// Helper function to retrieve all image data and combine chunks.
+/// Only used where the whole image is needed in memory (e.g. to resize it);
+/// `GET /image/:id` streams the chunks instead.
+async fn get_full_image_data(state: &Arc<AppState>, id: Hash) -> Result<ImageResult, AppError> {
+    let layout = ImageLayout::load(&state.client, id).await?;
+    println!("Fetching raw data for {} (MIME: {})", layout.filename, layout.mime_type);
+
+    let keys = chunk_keys(state, &layout, 1..=layout.parts).await?;
+    let chunks: Vec<(u64, Bytes)> = fetch_chunks(state.clone(), id, keys).try_collect().await?;
+    println!("Combined {} chunks.", chunks.len());
+
+    let mut image_data = Vec::with_capacity(layout.size.unwrap_or(0) as usize);
+    for (_, chunk) in chunks {
+        image_data.extend_from_slice(&chunk);
+    }
+    if layout.sha256.is_some_and(|expected| expected != sha256_hex(&image_data)) {
+        return Err(IntegrityError::ChecksumMismatch { id }.into());
+    }
 
     Ok(ImageResult {
         id,
         image_data,
-        filename,
-        mimetype: mime_type,
+        filename: layout.filename,
+        mimetype: layout.mime_type,
+        tags: layout.tags,
+        expires_at_block: layout.expires_at_block,
     })
 }
 
@@ -193,7 +544,15 @@ async fn serve_html() -> Html<&'static str> {
         </head>
         <body>
             <h1>Upload an Image</h1>
-            <form action="http://localhost:3000/upload" method="POST" enctype="multipart/form-data">
+            <form action="/upload" method="POST" enctype="multipart/form-data">
+                <div>
+                    <label for="btl">Lifetime in blocks (leave empty for the server default):</label>
+                    <input type="number" id="btl" name="btl" min="1" />
+                </div>
+                <div>
+                    <input type="checkbox" id="strip_metadata" name="strip_metadata" />
+                    <label for="strip_metadata">Remove EXIF/XMP metadata (camera details, location) from the stored image</label>
+                </div>
                 <div>
                     <label for="imageFile">Choose image:</label>
                     <input type="file" id="imageFile" name="imageFile" accept="image/*" required />
@@ -221,275 +580,1396 @@ async fn serve_html() -> Html<&'static str> {
                 </div>
                 <button type="submit">Upload</button>
             </form>
+            <h2>Live updates</h2>
+            <ul id="events"></ul>
+            <script>
+                const list = document.getElementById("events");
+                const source = new EventSource("/events");
+                for (const kind of ["created", "deleted", "expired"]) {
+                    source.addEventListener(kind, (message) => {
+                        const event = JSON.parse(message.data);
+                        if (event.entity_type !== "image") return;
+                        const item = document.createElement("li");
+                        const link = document.createElement("a");
+                        link.href = "/image/" + event.entity_key;
+                        link.textContent = event.filename || event.entity_key;
+                        item.append(kind + " at block " + event.block + ": ", link);
+                        list.prepend(item);
+                    });
+                }
+                source.addEventListener("resync", () => location.reload());
+            </script>
         </body>
         </html>
     "#)
 }
 
 /// The POST handler for the image upload form.
+/// The image is never held in memory as a whole: it is chunked into entities while it streams
+/// in and spooled to a temporary file from which the thumbnail is decoded afterwards.
+/// If anything fails, every entity created so far is deleted again, so a failed upload
+/// never leaves a partial image behind.
+///
+/// An optional `btl` field sets the lifetime in blocks of every entity of the upload. Because
+/// chunks are created while the file streams in, it has to come before `imageFile`.
+///
+/// A `strip_metadata` checkbox (which also has to come before `imageFile`) removes EXIF and
+/// XMP metadata from the stored original. Stripping needs the whole file, so in that case it
+/// is only spooled while it streams in, and chunked from memory once it has arrived.
+///
+/// The camera make and model and the capture time are read from the EXIF data (before any
+/// stripping) into `camera_make`, `camera_model` and `captured_at` annotations, and the
+/// image's dimensions into numeric `width` and `height` annotations.
+///
+/// Uploads larger than `max_upload_size`, or than `max_chunks` chunks, are refused with a 413,
+/// and ones that go over the client's daily quota with a 429, as soon as the limit is crossed.
 async fn upload_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<Identity>>,
+    multipart: Multipart,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let mut upload = None;
+    let identity = identity.map(|Extension(identity)| identity);
+    let result = store_upload(&state, addr.ip(), identity.as_ref(), multipart, &mut upload).await;
+    if result.is_err() && let Some(upload) = &upload {
+        upload.rollback().await;
+    }
+    result.map(Json)
+}
+
+/// Does the actual work of `upload_handler`. `upload` is filled in as soon as the image starts
+/// streaming so the caller can roll it back on error.
+/// The image counts against `client`'s daily upload quota as it arrives, and everything stored
+/// is annotated with the `identity` of the uploader, if known.
+async fn store_upload<'a>(
+    state: &'a AppState,
+    client: IpAddr,
+    identity: Option<&Identity>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
-    let mut filename: Option<String> = None;
-    let mut tags: Option<String> = None;
-    let mut custom_annotations = vec![];
-    let mut image_bytes: Option<Vec<u8>> = None;
+    upload: &mut Option<ChunkedUpload<'a>>,
+) -> Result<serde_json::Value, AppError> {
+    let mut form = UploadForm::new(&state.config);
+    let mut spool: Option<tokio::fs::File> = None;
     let mut mime_type: Option<String> = None;
 
-    // --- 1. VALIDATE AND PARSE THE INPUT ---
+    // --- 1. VALIDATE AND PARSE THE INPUT, STREAMING THE IMAGE INTO CHUNK ENTITIES ---
     println!("Parsing multipart form data...");
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        
-        match name.as_str() {
-            "filename" => {
-                filename = Some(field.text().await.unwrap());
-            }
-            "tags" => {
-                tags = Some(field.text().await.unwrap());
-            }
-            "imageFile" => {
-                println!("Reading image file...");
-                // We'll get the content type first, which borrows the field,
-                // and then get the bytes, which moves the field.
-                mime_type = Some(field.content_type().unwrap().to_string());
-                image_bytes = Some(field.bytes().await.unwrap().to_vec());
-                println!("Image size: {} bytes", image_bytes.as_ref().unwrap().len());
-            }
-            custom_key if custom_key.starts_with("custom_key") => {
-                let value_name = custom_key.replace("key", "value");
-                let value = multipart.next_field().await.unwrap().unwrap().text().await.unwrap();
-                
-                if !value.is_empty() {
-                    custom_annotations.push(Annotation::new(value_name.replace("custom_value", ""), value));
-                }
-            }
-            _ => {
-                // Ignore other fields
+    while let Some(field) = multipart.next_field().await? {
+        let Some(mut field) = form.read(&state.config, field).await? else {
+            continue;
+        };
+
+        println!("Streaming image file...");
+        // Don't trust the declared content type: read enough of the file to recognize its
+        // format, and refuse anything we can't decode before a single entity is created.
+        let (head, mime) = read_image_head(state, &mut field).await?;
+
+        // Stop as soon as the upload goes over a limit, before more entities are paid for.
+        let mut received = head.len();
+        check_upload_size(&state.config, received)?;
+        state.upload_quota.charge(client, head.len())?;
+
+        let strip = form.strip;
+        let chunked = upload.insert(ChunkedUpload::new(&state.client, &state.config, form.btl, mime.clone()));
+        let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+        file.write_all(&head).await?;
+        if !strip {
+            chunked.push(&head).await?;
+        }
+
+        while let Some(piece) = field.chunk().await? {
+            received += piece.len();
+            check_upload_size(&state.config, received)?;
+            state.upload_quota.charge(client, piece.len())?;
+            file.write_all(&piece).await?;
+            if !strip {
+                chunked.push(&piece).await?;
             }
         }
+        if !strip {
+            println!("Image size: {} bytes", chunked.total_len());
+        }
+
+        mime_type = Some(mime);
+        spool = Some(file);
     }
+    form.finish()?;
 
-    let original_image_bytes = match image_bytes {
-        Some(bytes) => bytes,
-        None => return (StatusCode::BAD_REQUEST, "No image file was uploaded.").into_response(),
+    let (upload, mut spool) = match (upload.as_mut(), spool) {
+        (Some(upload), Some(spool)) => (upload, spool),
+        _ => return Err(AppError::BadRequest("No image file was uploaded.".to_string())),
     };
-    let tags_str = tags.unwrap_or_else(|| "".to_string());
+    let UploadForm { filename, tags, custom_annotations, btl, strip, .. } = form;
+    let tags = normalize_tags(tags.as_deref());
+    check_tags(&tags)?;
     let original_filename = filename.unwrap_or_else(|| "image.png".to_string());
-    let mime_type_str = mime_type.unwrap_or_else(|| "image/png".to_string());
+    let mime_type_str = mime_type.unwrap_or_default();
 
-    println!("Received upload with tags: \"{}\"", tags_str);
+    if strip {
+        let mut data = Vec::new();
+        spool.seek(std::io::SeekFrom::Start(0)).await?;
+        spool.read_to_end(&mut data).await?;
+        let original_len = data.len();
+        let mime = mime_type_str.clone();
+        let stripped = tokio::task::spawn_blocking(move || strip_metadata(data.into(), &mime))
+            .await
+            .map_err(|e| AppError::Internal(format!("Metadata stripping task failed: {}", e)))?
+            .map_err(strip_failed)?;
+        println!("Stripped metadata: {} -> {} bytes", original_len, stripped.len());
+        upload.push(&stripped).await?;
+    }
+
+    println!("Received upload with tags: {:?}", tags);
 
     let mut string_annotations = vec![
         Annotation::new("type", "image"),
-        Annotation::new("app", "golem-images-0.1"),
+        Annotation::new("app", state.config.app_tag.clone()),
         Annotation::new("filename", original_filename.clone()),
         Annotation::new("mime_type", mime_type_str.clone()),
-        Annotation::new("tag", tags_str.clone())
     ];
-
-    // let tag_list: Vec<&str> = tags_str.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
-    // for tag in tag_list {
-    //     string_annotations.push(Annotation::new("tag", tag));
-    // }
-    //string_annotations.push(Annotation::new("tag", &tags_str));
+    string_annotations.extend(tag_annotations(&tags));
+    string_annotations.extend(identity.map(Identity::annotation));
 
     // Combine custom annotations
     string_annotations.extend(custom_annotations);
-    
-    // --- 2. RESIZE THE IMAGE FOR A THUMBNAIL ---
-    let image_data = image::load_from_memory(&original_image_bytes).unwrap();
-    let resized_image_data = image_data.resize_to_fill(100, 100, FilterType::Lanczos3);
-    
-    // Use a cursor to write to the Vec<u8> in memory
-    let mut thumbnail_bytes_cursor = Cursor::new(Vec::new());
-    resized_image_data.write_to(&mut thumbnail_bytes_cursor, ImageFormat::Jpeg).unwrap();
-    let thumbnail_bytes = thumbnail_bytes_cursor.into_inner();
-    let thumbnail_len = thumbnail_bytes.len();
 
-    println!("Resized image size: {} bytes", thumbnail_len);
+    // --- 2. RESIZE THE IMAGE INTO ITS RENDITIONS ---
+    // Decode once, then render every preset on its own blocking thread. The EXIF fields
+    // are read from the spooled original, so they are found even if the stored copy is stripped.
+    let mut spool = std::io::BufReader::new(spool.into_std().await);
+    let (image, exif_annotations) = tokio::task::spawn_blocking(move || {
+        let exif_annotations = read_exif(&mut spool);
+        decode_upload(spool).map(|image| (image, exif_annotations))
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Decoding task failed: {}", e)))??;
+    let (width, height) = (image.width() as u64, image.height() as u64);
+    let rendered = render_renditions(state, image).await?;
 
-    // --- 3. CHUNK THE ORIGINAL IMAGE IF NEEDED ---
-    const CHUNK_SIZE: usize = 100000;
-    let chunks: Vec<&[u8]> = original_image_bytes.chunks(CHUNK_SIZE).collect();
-    println!("Number of chunks: {}", chunks.len());
+    // --- 3. FINALIZE THE MAIN ENTITY AND ADD THE THUMBNAIL ---
+    // `uploaded_at` (seconds since the Unix epoch) lets `/search` filter by upload time.
+    let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    string_annotations.extend(exif_annotations.iter().cloned());
+    let numeric_annotations = vec![
+        Annotation::new("uploaded_at", uploaded_at),
+        Annotation::new("width", width),
+        Annotation::new("height", height),
+    ];
+    let FinishedUpload { main_entity_key, parts, expires_at_block } = upload
+        .finish(string_annotations, numeric_annotations)
+        .await?;
+    println!("Number of chunks: {}", parts);
 
-    let mut create_entities = vec![];
+    // Renditions can be almost as large as a chunk, so each is created in its own transaction.
+    let thumb_creates = rendition_creates(
+        state,
+        main_entity_key,
+        &rendered,
+        RenditionInfo {
+            filename: &original_filename,
+            tags: &tags,
+            uploaded_at,
+            btl,
+            identity,
+        },
+    );
+    let mut thumb_receipts = vec![];
+    for create in thumb_creates {
+        let receipt = state.client.create_entities(vec![create]).await.map_err(AppError::rpc)?.remove(0);
+        upload.track(receipt.entity_key);
+        thumb_receipts.push(receipt);
+    }
+    println!("Created {} thumbnail entities: {:?}", thumb_receipts.len(), thumb_receipts);
 
-    // First, create the main image entity (first chunk)
-    let main_entity_create = Create {
-        data: chunks[0].to_vec().into(),
-        btl: 25,
-        string_annotations: string_annotations.clone(),
-        numeric_annotations: vec![
-            Annotation::new("part", 1u64),
-            Annotation::new("part_of", chunks.len() as u64),
-        ],
-    };
-    let receipts = state.client.create_entities(vec![main_entity_create]).await;
-    let receipts = match receipts {
-        Ok(r) => r,
+    let thumbnails = renditions_json(state, &rendered, thumb_receipts.iter().map(|receipt| receipt.entity_key));
+    let expires_at_block = thumb_receipts
+        .iter()
+        .map(|receipt| receipt.expiration_block)
+        .fold(expires_at_block, u64::min);
+
+    // --- 4. SEND A SUCCESS RESPONSE ---
+    Ok(json!({
+        "message": "File processed successfully!",
+        "originalSize": upload.total_len(),
+        "resizedSize": rendered[0].data.len(),
+        "tags": tags,
+        "entity_key": main_entity_key.to_string(),
+        "width": width,
+        "height": height,
+        "exif": exif_annotations
+            .iter()
+            .map(|annotation| (annotation.key.clone(), annotation.value.clone()))
+            .collect::<BTreeMap<_, _>>(),
+        "metadata_stripped": strip,
+        "thumbnails": thumbnails,
+        "btl": btl,
+        "expires_at_block": expires_at_block,
+    }))
+}
+
+// Handler for the `POST /signed-uploads` route.
+// The first step of an upload that is signed by the uploader's own wallet instead of ours, so
+// the image ends up owned by them. Takes the same form as `/upload` plus an `owner` field with
+// the address that will sign; as there, `btl` and `strip_metadata` must come before `imageFile`.
+// The image is checked, rendered and annotated here, and the response holds an unsigned
+// transaction that creates its (provisional) main entity.
+// Sign it and send it to `POST /signed-uploads/:id` to get the rest of the transactions.
+async fn prepare_signed_upload(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<Identity>>,
+    mut multipart: Multipart,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let mut form = UploadForm::new(&state.config);
+    let mut image: Option<(Vec<u8>, String)> = None;
+
+    // --- 1. VALIDATE AND PARSE THE INPUT ---
+    // The whole image is needed to prepare the transactions, so it is read into memory. While
+    // the upload waits for them to be signed, it is only kept in a temporary file.
+    while let Some(field) = multipart.next_field().await? {
+        let Some(mut field) = form.read(&state.config, field).await? else {
+            continue;
+        };
+        let (mut data, mime) = read_image_head(&state, &mut field).await?;
+        check_upload_size(&state.config, data.len())?;
+        state.upload_quota.charge(addr.ip(), data.len())?;
+        while let Some(piece) = field.chunk().await? {
+            check_upload_size(&state.config, data.len() + piece.len())?;
+            state.upload_quota.charge(addr.ip(), piece.len())?;
+            data.extend_from_slice(&piece);
+        }
+        image = Some((data, mime));
+    }
+    form.finish()?;
+
+    let UploadForm { owner, filename, tags, custom_annotations, btl, strip, .. } = form;
+    let owner = owner.ok_or_else(|| AppError::BadRequest("The owner field (the address that will sign) is required.".to_string()))?;
+    let owner: Address = owner.trim().parse().map_err(|_| AppError::BadRequest(format!("Invalid owner address: {}", owner)))?;
+    let (original, mime_type) = image.ok_or_else(|| AppError::BadRequest("No image file was uploaded.".to_string()))?;
+    let tags = normalize_tags(tags.as_deref());
+    check_tags(&tags)?;
+    let filename = filename.unwrap_or_else(|| "image.png".to_string());
+    let identity = identity.map(|Extension(identity)| identity);
+    println!("POST /signed-uploads called by {} for {} ({} bytes)", owner, filename, original.len());
+
+    // --- 2. READ THE EXIF DATA, STRIP IT IF ASKED TO, AND RENDER THE THUMBNAILS ---
+    let mime = mime_type.clone();
+    let (data, decoded, exif_annotations) = tokio::task::spawn_blocking(move || {
+        let exif_annotations = read_exif(&mut std::io::Cursor::new(&original[..]));
+        let data = if strip { strip_metadata(original.into(), &mime).map_err(strip_failed)? } else { Bytes::from(original) };
+        let decoded = decode_upload(std::io::Cursor::new(&data[..])).map_err(AppError::from)?;
+        Ok::<_, AppError>((data, decoded, exif_annotations))
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Decoding task failed: {}", e)))??;
+    let (width, height) = (decoded.width() as u64, decoded.height() as u64);
+    let rendered = render_renditions(&state, decoded).await?;
+
+    // --- 3. PREPARE THE FIRST TRANSACTION ---
+    let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut string_annotations = vec![
+        Annotation::new("type", "image"),
+        Annotation::new("app", state.config.app_tag.clone()),
+        Annotation::new("filename", filename.clone()),
+        Annotation::new("mime_type", mime_type.clone()),
+    ];
+    string_annotations.extend(tag_annotations(&tags));
+    string_annotations.extend(identity.as_ref().map(Identity::annotation));
+    string_annotations.extend(custom_annotations);
+    string_annotations.extend(exif_annotations.iter().cloned());
+    let mut numeric_annotations = vec![
+        Annotation::new("uploaded_at", uploaded_at),
+        Annotation::new("width", width),
+        Annotation::new("height", height),
+    ];
+    let first_chunk = &data[..data.len().min(state.config.chunk_size)];
+    add_layout_annotations(
+        &state.config,
+        &mut string_annotations,
+        &mut numeric_annotations,
+        Layout {
+            parts: data.chunks(state.config.chunk_size).count() as u64,
+            size: data.len(),
+            sha256: sha256_hex(&data),
+            first_chunk,
+        },
+    );
+
+    let main_create = provisional_main_create(&state.config, btl, &mime_type, first_chunk.to_vec());
+    let payload = encode_transaction(&[main_create], &[]);
+    let chain_id = state.relay.chain_id().await?;
+    let transaction = unsigned_transaction(&payload, chain_id);
+    let mut spool = tokio::fs::File::from_std(tempfile::tempfile()?);
+    spool.write_all(&data).await?;
+    let upload_id = state.pending_uploads.insert(PendingUpload {
+        owner,
+        chain_id,
+        stage: Stage::Main,
+        expected: vec![keccak256(&payload)],
+        btl,
+        data: spool,
+        size: data.len(),
+        mime_type,
+        filename,
+        tags,
+        identity,
+        uploaded_at,
+        string_annotations,
+        numeric_annotations,
+        rendered,
+        width,
+        height,
+        exif_annotations,
+        metadata_stripped: strip,
+    })?;
+    println!("Prepared signed upload {} for {}", upload_id, owner);
+
+    // --- 4. SEND THE TRANSACTION TO SIGN ---
+    Ok(Json(json!({
+        "upload_id": upload_id,
+        "owner": owner.to_string(),
+        "transactions": [transaction],
+    })))
+}
+
+// Handler for the `POST /signed-uploads/:id` route.
+// Takes `{"signed_transactions": ["0x..."]}`: the transactions handed out by the previous step,
+// signed by the owner in the same order. Each one is checked against what was prepared before
+// it is relayed to the node. After the main entity is created, the response lists the
+// transactions for the remaining chunks, the thumbnails and the final update of the main
+// entity; once those are in, it describes the stored image like `/upload` does, plus its owner.
+// If relaying fails, what was stored so far expires with its `btl` and the upload has to start over.
+async fn submit_signed_upload(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    body: Result<Json<SignedTransactions>, JsonRejection>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let Json(body) = body?;
+    println!("POST /signed-uploads/{} called with {} transactions", id, body.signed_transactions.len());
+    let mut pending = state.pending_uploads.take(&id)?;
+
+    // --- 1. CHECK THE SIGNED TRANSACTIONS AGAINST THE PREPARED ONES ---
+    // Until something has been relayed, a mistake can be corrected by submitting again.
+    let checked = check_signed_transactions(&pending, &body.signed_transactions);
+    let raw_transactions = match checked {
+        Ok(raw_transactions) => raw_transactions,
         Err(e) => {
-            eprintln!("Error creating main entity: {:?}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create main entity").into_response();
+            state.pending_uploads.insert_again(id, pending);
+            return Err(e);
         }
     };
-    let main_entity_key = Some(receipts[0].entity_key);
-    println!("Created main entity: {:?}", main_entity_key);
 
-    // Create the thumbnail entity
-    let thumb_create = Create {
-        data: thumbnail_bytes.into(),
-        btl: 25,
-        string_annotations: vec![
-            Annotation::new("parent", main_entity_key.unwrap().to_string()),
-            Annotation::new("type", "thumbnail"),
-            Annotation::new("app", "golem-images-0.1"),
-            Annotation::new("resize", "100x100"),
-            Annotation::new("filename", format!("thumb_{}", original_filename)),
-            Annotation::new("mime_type", "image/jpeg"),
-        ],
-        numeric_annotations: vec![],
-    };
-    let thumb_receipts = state.client.create_entities(vec![thumb_create]).await;
-    match thumb_receipts {
-        Ok(r) => println!("Created thumbnail entity: {:?}", r),
-        Err(e) => eprintln!("Error creating thumbnail: {:?}", e),
-    };
+    // --- 2. RELAY THEM ---
+    // Send all of them first: they have consecutive nonces, and the node can include them in one block.
+    let mut tx_hashes = vec![];
+    for raw in &raw_transactions {
+        tx_hashes.push(state.relay.send(raw).await?);
+    }
+    let mut stored = vec![];
+    for tx_hash in tx_hashes {
+        stored.push(state.relay.wait_for_stored(tx_hash).await?);
+    }
 
-    // If there are more chunks, create an entity for each
-    if chunks.len() > 1 {
-        for (i, chunk) in chunks.iter().skip(1).enumerate() {
-            let chunk_create = Create {
-                data: chunk.to_vec().into(),
-                btl: 25,
-                string_annotations: vec![
-                    Annotation::new("parent", main_entity_key.unwrap().to_string()),
-                    Annotation::new("type", "image_chunk"),
-                    Annotation::new("app", "golem-images-0.1"),
-                    Annotation::new("filename", original_filename.clone()),
-                    Annotation::new("mime_type", mime_type_str.clone()),
-                ],
-                numeric_annotations: vec![
-                    Annotation::new("part", (i + 2) as u64), // parts are 1-based
-                    Annotation::new("part_of", chunks.len() as u64),
-                ],
+    match pending.stage {
+        // --- 3. PREPARE THE TRANSACTIONS FOR THE REST OF THE IMAGE ---
+        Stage::Main => {
+            let main_entity_key = stored[0]
+                .first()
+                .ok_or_else(|| AppError::Upstream("The main entity wasn't created.".to_string()))?
+                .entity_key;
+            println!("Created main entity {} for signed upload {}", main_entity_key, id);
+
+            let chunk_size = state.config.chunk_size;
+            let data = pending.read_data().await?;
+            let mut payloads: Vec<Bytes> = data
+                .chunks(chunk_size)
+                .enumerate()
+                .skip(1)
+                .map(|(i, chunk)| {
+                    let create = chunk_create(&state.config, pending.btl, main_entity_key, &pending.mime_type, i as u64 + 1, chunk.to_vec());
+                    encode_transaction(&[create], &[])
+                })
+                .collect();
+            let thumb_creates = rendition_creates(
+                &state,
+                main_entity_key,
+                &pending.rendered,
+                RenditionInfo {
+                    filename: &pending.filename,
+                    tags: &pending.tags,
+                    uploaded_at: pending.uploaded_at,
+                    btl: pending.btl,
+                    identity: pending.identity.as_ref(),
+                },
+            );
+            // Thumbnails can be large too, so each gets its own transaction.
+            payloads.extend(thumb_creates.into_iter().map(|create| encode_transaction(&[create], &[])));
+            let finalize = Update {
+                entity_key: main_entity_key,
+                data: Bytes::copy_from_slice(&data[..data.len().min(chunk_size)]),
+                btl: pending.btl,
+                string_annotations: pending.string_annotations.clone(),
+                numeric_annotations: pending.numeric_annotations.clone(),
             };
-            create_entities.push(chunk_create);
+            payloads.push(encode_transaction(&[], &[finalize]));
+
+            let transactions: Vec<serde_json::Value> = payloads
+                .iter()
+                .map(|payload| unsigned_transaction(payload, pending.chain_id))
+                .collect();
+            pending.stage = Stage::Parts { main_entity_key };
+            pending.expected = payloads.iter().map(keccak256).collect();
+            state.pending_uploads.insert_again(id.clone(), pending);
+
+            Ok(Json(json!({
+                "upload_id": id,
+                "entity_key": main_entity_key.to_string(),
+                "transactions": transactions,
+            })))
         }
+        // --- 4. SEND A SUCCESS RESPONSE ---
+        Stage::Parts { main_entity_key } => {
+            let chunks = pending.size.div_ceil(state.config.chunk_size) - 1;
+            let thumb_keys = stored[chunks..chunks + pending.rendered.len()]
+                .iter()
+                .map(|stored| stored.first().map(|stored| stored.entity_key))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| AppError::Upstream("A thumbnail wasn't created.".to_string()))?;
+            let expires_at_block = stored.iter().flatten().map(|stored| stored.expires_at_block).min().unwrap_or_default();
+            println!("Finished signed upload {}: {}", id, main_entity_key);
 
-        // Send all remaining chunks in a single API call
-        let chunk_receipts = state.client.create_entities(create_entities).await;
-        println!("{:?}", chunk_receipts);
-        match chunk_receipts {
-            Ok(r) => println!("Created {} chunk entities.", r.len()),
-            Err(e) => eprintln!("Error creating chunks: {:?}", e),
-        };
+            Ok(Json(json!({
+                "message": "File processed successfully!",
+                "originalSize": pending.size,
+                "resizedSize": pending.rendered[0].data.len(),
+                "tags": pending.tags,
+                "entity_key": main_entity_key.to_string(),
+                "owner": pending.owner.to_string(),
+                "width": pending.width,
+                "height": pending.height,
+                "exif": pending
+                    .exif_annotations
+                    .iter()
+                    .map(|annotation| (annotation.key.clone(), annotation.value.clone()))
+                    .collect::<BTreeMap<_, _>>(),
+                "metadata_stripped": pending.metadata_stripped,
+                "thumbnails": renditions_json(&state, &pending.rendered, thumb_keys.into_iter()),
+                "btl": pending.btl,
+                "expires_at_block": expires_at_block,
+            })))
+        }
     }
+}
 
-    // --- 4. SEND A SUCCESS RESPONSE ---
-    (StatusCode::OK, Json(json!({
-        "message": "File processed successfully!",
-        "originalSize": original_image_bytes.len(),
-        "resizedSize": thumbnail_len,
-        "tags": tags_str,
-        "entity_key": main_entity_key.unwrap().to_string()
-    }))).into_response()
+/// Helper function that decodes the submitted transactions and checks each one against the
+/// transaction prepared for its position.
+fn check_signed_transactions(pending: &PendingUpload, signed: &[String]) -> Result<Vec<Vec<u8>>, AppError> {
+    if signed.len() != pending.expected.len() {
+        return Err(AppError::BadRequest(format!(
+            "Expected {} signed transactions, got {}.",
+            pending.expected.len(),
+            signed.len()
+        )));
+    }
+    signed
+        .iter()
+        .zip(&pending.expected)
+        .map(|(signed, expected)| {
+            let raw = hex::decode(signed.trim().trim_start_matches("0x"))
+                .map_err(|_| AppError::BadRequest("Signed transactions must be hex encoded.".to_string()))?;
+            check_signed(&raw, pending.owner, pending.chain_id, *expected)?;
+            Ok(raw)
+        })
+        .collect()
+}
+
+/// Helper function that reads the start of an uploaded image and detects its type from it.
+/// The declared content type isn't trusted: anything we can't decode, or that isn't one of
+/// the allowed types, is refused before a single entity is created. Returns the bytes read
+/// so far together with the detected MIME type.
+async fn read_image_head(state: &AppState, field: &mut Field<'_>) -> Result<(Vec<u8>, String), AppError> {
+    let declared = field.content_type().map(str::to_string);
+    let mut head = Vec::new();
+    while head.len() < SNIFF_LEN {
+        match field.chunk().await? {
+            Some(piece) => head.extend_from_slice(&piece),
+            None => break,
+        }
+    }
+    let mime = sniff_mime_type(&head)
+        .ok_or_else(|| AppError::UnsupportedMediaType("The uploaded file is not a recognized image format.".to_string()))?;
+    if !state.config.allowed_types.iter().any(|allowed| allowed == mime) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "{} uploads are not accepted; allowed types: {}.",
+            mime,
+            state.config.allowed_types.join(", ")
+        )));
+    }
+    if declared.as_deref().is_some_and(|declared| declared != mime) {
+        println!("Declared content type {:?} doesn't match the detected {}", declared, mime);
+    }
+    Ok((head, mime.to_string()))
+}
+
+/// Helper function that turns a failure of `strip_metadata` into an error response. It only fails
+/// on files it can't parse, which is the client's doing.
+fn strip_failed(e: upload::UploadError) -> AppError {
+    AppError::UnsupportedMediaType(format!("The image is malformed, its metadata can't be removed: {}", e))
+}
+
+/// Helper function that renders every configured rendition of `image`, each on its own blocking thread.
+/// Each one is stored as a single entity, so it must not be larger than `chunk_size`.
+async fn render_renditions(state: &AppState, image: DynamicImage) -> Result<Vec<Rendered>, AppError> {
+    let image = Arc::new(image);
+    let renditions = &state.config.renditions;
+    let rendered = try_join_all(renditions.iter().cloned().map(|rendition| {
+        let image = image.clone();
+        tokio::task::spawn_blocking(move || render(&image, &rendition))
+    }))
+    .await
+    .map_err(|e| AppError::Internal(format!("Thumbnail task failed: {}", e)))?
+    .into_iter()
+    .collect::<Result<Vec<Rendered>, _>>()?;
+
+    for (rendition, output) in renditions.iter().zip(&rendered) {
+        println!("Rendered {} as {}x{} ({} bytes)", rendition, output.width, output.height, output.data.len());
+        check_rendition_size(&state.config, rendition, output.data.len())?;
+    }
+    Ok(rendered)
+}
+
+/// What the thumbnail entities of a new upload share with its main entity.
+struct RenditionInfo<'a> {
+    filename: &'a str,
+    tags: &'a [String],
+    uploaded_at: u64,
+    btl: u64,
+    identity: Option<&'a Identity>,
+}
+
+/// Helper function that turns the output of `render_renditions` into thumbnail entities of `parent`.
+/// The first one is the gallery thumbnail and keeps the `thumb_` filename prefix; the others are
+/// prefixed with their size.
+fn rendition_creates(state: &AppState, parent: Hash, rendered: &[Rendered], info: RenditionInfo) -> Vec<Create> {
+    state
+        .config
+        .renditions
+        .iter()
+        .zip(rendered)
+        .enumerate()
+        .map(|(i, (rendition, output))| Create {
+            data: output.data.clone().into(),
+            btl: info.btl,
+            string_annotations: vec![
+                Annotation::new("parent", parent.to_string()),
+                Annotation::new("type", "thumbnail"),
+                Annotation::new("app", state.config.app_tag.clone()),
+                Annotation::new("resize", rendition.resize()),
+                Annotation::new("fit", rendition.fit.as_str()),
+                Annotation::new(
+                    "filename",
+                    if i == 0 {
+                        format!("thumb_{}", info.filename)
+                    } else {
+                        format!("{}_{}", rendition.resize(), info.filename)
+                    },
+                ),
+                Annotation::new("mime_type", rendition.encoding.format.mime_type()),
+            ]
+            .into_iter()
+            .chain(tag_annotations(info.tags))
+            .chain(info.identity.map(Identity::annotation))
+            .collect(),
+            numeric_annotations: vec![
+                Annotation::new("uploaded_at", info.uploaded_at),
+                Annotation::new("width", output.width as u64),
+                Annotation::new("height", output.height as u64),
+            ],
+        })
+        .collect()
+}
+
+/// Helper function that describes the stored renditions of an upload for the response.
+fn renditions_json(state: &AppState, rendered: &[Rendered], keys: impl Iterator<Item = Hash>) -> Vec<serde_json::Value> {
+    state
+        .config
+        .renditions
+        .iter()
+        .zip(rendered)
+        .zip(keys)
+        .map(|((rendition, output), key)| json!({
+            "entity_key": key.to_string(),
+            "resize": rendition.resize(),
+            "fit": rendition.fit.as_str(),
+            "mime_type": rendition.encoding.format.mime_type(),
+            "width": output.width,
+            "height": output.height,
+            "size": output.data.len(),
+        }))
+        .collect()
 }
 
 // Handler for the `GET /thumbnails` route.
-async fn get_thumbnails(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let query = "type=\"thumbnail\" && app=\"golem-images-0.1\"";
+// Returns every gallery thumbnail (the first configured rendition) with its parent key and
+// metadata, so the gallery doesn't need a `/parent/:thumbid` call per thumbnail. The metadata (and, with `?data=true`, the thumbnail
+// itself) is fetched with up to `chunk_fetch_concurrency` requests in flight.
+async fn get_thumbnails(
+    State(state): State<Arc<AppState>>,
+    params: Result<axum::extract::Query<ThumbnailsParams>, QueryRejection>,
+) -> Result<Json<Vec<serde_json::Value>>, AppError> {
+    let axum::extract::Query(params) = params?;
+    let query = Query::eq("type", "thumbnail")
+        .and(Query::eq("app", state.config.app_tag.as_str()))
+        .and(Query::eq("resize", state.config.renditions[0].resize()));
     
     println!("GET /thumbnails called. Executing query: {}", query);
 
-    match state.client.query_entity_keys(query).await {
-        Ok(keys) => Json(keys.into_iter().map(|key| key.to_string()).collect::<Vec<_>>()).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error querying thumbnails: {}", e)).into_response(),
-    }
+    let keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+
+    let thumbnails = stream::iter(keys)
+        .map(|key| {
+            let state = &state;
+            async move {
+                let metadata = state.client.get_entity_metadata(key).await.map_err(AppError::rpc)?;
+                let string_annotation = |name: &str| {
+                    metadata
+                        .string_annotations
+                        .iter()
+                        .find(|annot| annot.key == name)
+                        .map(|annot| annot.value.clone())
+                };
+                let numeric_annotation = |name: &str| {
+                    metadata
+                        .numeric_annotations
+                        .iter()
+                        .find(|annot| annot.key == name)
+                        .map(|annot| annot.value)
+                };
+                let resize = string_annotation("resize");
+                // The `resize` box is only the real size for cropped thumbnails, which is all
+                // there was before the stored size was annotated.
+                let (width, height) = match (numeric_annotation("width"), numeric_annotation("height")) {
+                    (Some(width), Some(height)) => (Some(width), Some(height)),
+                    _ => resize
+                        .as_deref()
+                        .and_then(|resize| resize.split_once('x'))
+                        .and_then(|(w, h)| Some((w.parse::<u64>().ok()?, h.parse::<u64>().ok()?)))
+                        .unzip(),
+                };
+
+                let mut thumbnail = json!({
+                    "entity_key": key.to_string(),
+                    "parent": string_annotation("parent"),
+                    "filename": string_annotation("filename"),
+                    "resize": resize,
+                    "width": width,
+                    "height": height,
+                    "mime_type": string_annotation("mime_type"),
+                    "owner": metadata.owner.to_string(),
+                    "expires_at_block": metadata.expires_at_block,
+                });
+                if params.data {
+                    let data = state.client.get_storage_value::<Vec<u8>>(key).await.map_err(AppError::rpc)?;
+                    thumbnail["data"] = json!(BASE64.encode(data));
+                }
+                Ok::<_, AppError>(thumbnail)
+            }
+        })
+        .buffered(state.config.chunk_fetch_concurrency)
+        .try_collect()
+        .await?;
+    Ok(Json(thumbnails))
 }
 
 // Handler for the `GET /parent/:thumbid` route.
 async fn get_parent(
     State(state): State<Arc<AppState>>,
     Path(thumbid): Path<String>,
-) -> impl IntoResponse {
+) -> Result<String, AppError> {
 
-    let entity_key = parse_b256(&thumbid);
+    let entity_key = parse_b256(&thumbid)?;
 
-    let metadata = state.client.get_entity_metadata(entity_key).await;
+    let metadata = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
 
-    match metadata {
-        Ok(md) => {
-            for annot in md.string_annotations {
-                if annot.key == "parent" {
-                    return (StatusCode::OK, annot.value).into_response();
-                }
-            }
-            (StatusCode::NOT_FOUND, "Parent key not found.").into_response()
-        }
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error fetching metadata: {}", e)).into_response(),
-    }
+    metadata
+        .string_annotations
+        .into_iter()
+        .find(|annot| annot.key == "parent")
+        .map(|annot| annot.value)
+        .ok_or_else(|| AppError::NotFound("Parent key not found.".to_string()))
 }
 
 // Handler for the `GET /image/:id` route.
+// Streams the chunks in order as they are fetched, and honours a single `Range` header
+// by fetching only the chunks that cover it.
 async fn get_full_image(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    let entity_key = parse_b256(&id);
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let entity_key = parse_b256(&id)?;
+
+    let mut layout = ImageLayout::load(&state.client, entity_key).await?;
+    let size = layout.resolve_size(&state).await?;
 
-    // Fetch and combine the image data
-    let image_result = get_full_image_data(&state.client, entity_key).await;
+    let range = match headers.get(RANGE).and_then(|value| value.to_str().ok()) {
+        Some(header) => match parse_range(header, size) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(CONTENT_RANGE, format!("bytes */{}", size))],
+                ).into_response());
+            }
+        },
+        None => None,
+    };
+
+    // Look up the chunks before sending any headers, so a missing chunk is still an error status.
+    let keys = chunk_keys(&state, &layout, parts_for_range(&layout, range)).await?;
 
-    match image_result {
-        Ok(result) => {
-            // Success: Return the image data with the correct MIME type
-            
-            // Axum's IntoResponse allows us to build a custom response with headers.
+    let content_type = layout.mime_type.clone();
+    let content_disposition = format!("inline; filename=\"{}\"", layout.filename);
+    let expires_at_block = layout.expires_at_block.to_string();
+    let body = Body::from_stream(stream_image(state.clone(), &layout, keys, range));
+
+    let response = match range {
+        Some((start, end)) => {
+            println!("GET /image/{} streaming bytes {}-{}/{}", id, start, end, size);
             (
+                StatusCode::PARTIAL_CONTENT,
                 [
-                    ("Content-Type", result.mimetype),
-                    ("Content-Disposition", format!("inline; filename=\"{}\"", result.filename)),
+                    ("Content-Type", content_type),
+                    ("Content-Disposition", content_disposition),
+                    ("Accept-Ranges", "bytes".to_string()),
+                    ("X-Expires-At-Block", expires_at_block),
+                    ("Content-Length", (end - start + 1).to_string()),
+                    ("Content-Range", format!("bytes {}-{}/{}", start, end, size)),
                 ],
-                // Convert Vec<u8> to axum::body::Bytes for the response body
-                Bytes::from(result.image_data), 
+                body,
             ).into_response()
         }
-        Err(e) => {
-            eprintln!("Error fetching image data: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to retrieve and combine image data.").into_response()
+        None => {
+            println!("GET /image/{} streaming {} parts ({} bytes)", id, layout.parts, size);
+            (
+                StatusCode::OK,
+                [
+                    ("Content-Type", content_type),
+                    ("Content-Disposition", content_disposition),
+                    ("Accept-Ranges", "bytes".to_string()),
+                    ("X-Expires-At-Block", expires_at_block),
+                    ("Content-Length", size.to_string()),
+                ],
+                body,
+            ).into_response()
+        }
+    };
+    Ok(response)
+}
+
+// Handler for the `DELETE /image/:id` route.
+// Deletes the image together with every thumbnail and chunk pointing at it through `parent`,
+// in a single transaction. Only the owner of the image (and, with authentication on, the
+// identity that uploaded it) can delete it, and only as a whole:
+// the key of a chunk or thumbnail is refused, as deleting one would leave the image broken.
+async fn delete_image(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    identity: Option<Extension<Identity>>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let entity_key = parse_b256(&id)?;
+    println!("DELETE /image/{} called.", id);
+
+    let metadata = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
+    ensure_owner(&state, entity_key, metadata.owner)?;
+    ensure_uploader(&state, entity_key, &metadata.string_annotations, identity.as_deref())?;
+    ensure_image(entity_key, &metadata.string_annotations)?;
+
+    let mut keys = vec![entity_key];
+    keys.extend(owned_children(&state, entity_key, metadata.owner, None).await?);
+
+    state.client.delete_entities(keys.clone()).await.map_err(AppError::rpc)?;
+    println!("Deleted {} entities.", keys.len());
+
+    Ok(Json(json!({
+        "message": "Image deleted successfully!",
+        "entity_key": entity_key.to_string(),
+        "deleted": keys.iter().map(|key| key.to_string()).collect::<Vec<_>>(),
+    })))
+}
+
+// Handler for the `PATCH /image/:id` route.
+// Changes the tags, filename and custom annotations of an image. The stored data, numeric
+// annotations and expiry are kept, and new tags or a new filename are carried over to the thumbnails.
+// All entities are updated in a single transaction. With authentication on, only the identity
+// that uploaded the image can change it.
+async fn patch_image(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    identity: Option<Extension<Identity>>,
+    params: Result<Json<PatchRequest>, JsonRejection>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let Json(params) = params?;
+    let entity_key = parse_b256(&id)?;
+    println!("PATCH /image/{} called.", id);
+
+    for key in params.annotations.keys() {
+        if key.is_empty() || RESERVED_ANNOTATIONS.contains(&key.as_str()) {
+            return Err(AppError::BadRequest(format!("Annotation {:?} can't be changed.", key)));
+        }
+    }
+    if params.filename.as_deref().is_some_and(|name| name.trim().is_empty()) {
+        return Err(AppError::BadRequest("filename must not be empty.".to_string()));
+    }
+
+    let metadata = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
+    ensure_owner(&state, entity_key, metadata.owner)?;
+    ensure_uploader(&state, entity_key, &metadata.string_annotations, identity.as_deref())?;
+    ensure_image(entity_key, &metadata.string_annotations)?;
+
+    // --- 1. APPLY THE CHANGES TO THE MAIN ENTITY'S ANNOTATIONS ---
+    let old_filename = metadata
+        .string_annotations
+        .iter()
+        .find(|annot| annot.key == "filename")
+        .map(|annot| annot.value.clone())
+        .unwrap_or_default();
+
+    let old_tags = stored_tags(&metadata.string_annotations);
+    let new_tags = params.tags.as_deref().map(|tags| normalize_tags(Some(tags)));
+    if let Some(tags) = &new_tags {
+        check_tags(tags)?;
+    }
+
+    let mut string_annotations: Vec<Annotation<String>> = metadata
+        .string_annotations
+        .into_iter()
+        .filter(|annot| match annot.key.as_str() {
+            "tag" => new_tags.is_none(),
+            "filename" => params.filename.is_none(),
+            key => !params.annotations.contains_key(key),
+        })
+        .collect();
+    if let Some(tags) = &new_tags {
+        string_annotations.extend(tag_annotations(tags));
+    }
+    if let Some(filename) = &params.filename {
+        string_annotations.push(Annotation::new("filename", filename.clone()));
+    }
+    for (key, value) in &params.annotations {
+        if let Some(value) = value {
+            string_annotations.push(Annotation::new(key.clone(), value.clone()));
         }
     }
+
+    let data = state.client.get_storage_value::<Vec<u8>>(entity_key).await.map_err(AppError::rpc)?;
+    let mut updates = vec![Update {
+        entity_key,
+        data: data.into(),
+        btl: remaining_btl(&state, metadata.expires_at_block).await?,
+        string_annotations: string_annotations.clone(),
+        numeric_annotations: metadata.numeric_annotations,
+    }];
+
+    // --- 2. CARRY NEW TAGS AND A NEW FILENAME OVER TO THE THUMBNAILS ---
+    // Thumbnail filenames are the image's filename with a prefix such as `thumb_` or `200x100_`.
+    let new_filename = params.filename.as_ref().filter(|name| **name != old_filename);
+    let new_tags = new_tags.filter(|tags| *tags != old_tags);
+    if new_filename.is_some() || new_tags.is_some() {
+        for thumb_key in owned_children(&state, entity_key, metadata.owner, Some("thumbnail")).await? {
+            let thumb = state.client.get_entity_metadata(thumb_key).await.map_err(AppError::rpc)?;
+            let thumb_data = state.client.get_storage_value::<Vec<u8>>(thumb_key).await.map_err(AppError::rpc)?;
+            let mut thumb_annotations: Vec<Annotation<String>> = thumb
+                .string_annotations
+                .into_iter()
+                .filter(|annot| new_tags.is_none() || annot.key != "tag")
+                .map(|annot| match new_filename {
+                    Some(filename) if annot.key == "filename" => {
+                        let prefix = annot.value.strip_suffix(old_filename.as_str()).unwrap_or("thumb_");
+                        Annotation::new("filename", format!("{}{}", prefix, filename))
+                    }
+                    _ => annot,
+                })
+                .collect();
+            if let Some(tags) = &new_tags {
+                thumb_annotations.extend(tag_annotations(tags));
+            }
+            updates.push(Update {
+                entity_key: thumb_key,
+                data: thumb_data.into(),
+                btl: remaining_btl(&state, thumb.expires_at_block).await?,
+                string_annotations: thumb_annotations,
+                numeric_annotations: thumb.numeric_annotations,
+            });
+        }
+    }
+
+    let receipts = state.client.update_entities(updates).await.map_err(AppError::rpc)?;
+    println!("Updated {} entities.", receipts.len());
+
+    let tags = stored_tags(&string_annotations);
+    let annotations: BTreeMap<String, String> = string_annotations
+        .into_iter()
+        .filter(|annot| annot.key != "tag")
+        .map(|annot| (annot.key, annot.value))
+        .collect();
+    Ok(Json(json!({
+        "message": "Image updated successfully!",
+        "entity_key": entity_key.to_string(),
+        "annotations": annotations,
+        "tags": tags,
+        "updated": receipts.iter().map(|r| r.entity_key.to_string()).collect::<Vec<_>>(),
+    })))
 }
 
 // Handler for the `POST /add-resize/:id` route.
+// Builds a new resized version of an existing image and stores it as a thumbnail of that image.
+// The key of a chunk or thumbnail is refused: only images get renditions, and only the ones we own.
 async fn add_resize(
-    //State(state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    identity: Option<Extension<Identity>>,
+    request: Request,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let params: ResizeRequest = parse_body(request).await?;
+    println!("POST /add-resize/{} called with width={:?} height={:?}", id, params.width, params.height);
+
+    let entity_key = parse_b256(&id)?;
+    let metadata = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
+    ensure_image(entity_key, &metadata.string_annotations)?;
+    ensure_owner(&state, entity_key, metadata.owner)?;
+    let format = params
+        .format
+        .as_deref()
+        .map_or(Ok(OutputFormat::Jpeg), str::parse)
+        .map_err(AppError::BadRequest)?;
+    let encoding = Encoding::new(format, params.quality).map_err(AppError::BadRequest)?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let resized = store_resized(&state, entity_key, params.width, params.height, FitMode::Exact, encoding, identity.as_ref()).await?;
+
+    Ok(Json(json!({
+        "message": "Resized image stored successfully!",
+        "entity_key": resized.entity_key.to_string(),
+        "parent": entity_key.to_string(),
+        "resize": resized.resize,
+        "mime_type": resized.mime_type,
+        "resizedSize": resized.data.len(),
+        "expires_at_block": resized.expires_at_block,
+    })))
+}
+
+/// Helper function that resizes the image `entity_key` and stores the result as a new thumbnail of it,
+/// expiring together with the image. Callers make sure the image is one we own, so the
+/// thumbnail is found by `owned_children` when the image is deleted or extended.
+/// Both dimensions are fitted into according to `fit`; a single dimension keeps the original aspect ratio.
+/// The result is encoded as `encoding` asks, and annotated with the `identity` that asked for it, if known.
+async fn store_resized(
+    state: &Arc<AppState>,
+    entity_key: Hash,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: FitMode,
+    encoding: Encoding,
+    identity: Option<&Identity>,
+) -> Result<StoredResize, AppError> {
+    if width.is_none() && height.is_none() {
+        return Err(AppError::BadRequest("No dimensions provided.".to_string()));
+    }
+    let limits = state.config.resize_limits;
+    for dim in [width, height].into_iter().flatten() {
+        if !limits.contains(dim) {
+            return Err(AppError::BadRequest(format!(
+                "Dimensions must be between {} and {} pixels.",
+                limits.min, limits.max
+            )));
+        }
+    }
+
+    // --- 1. REASSEMBLE THE ORIGINAL IMAGE ---
+    let original = get_full_image_data(state, entity_key).await?;
+    let image_data = image::load_from_memory(&original.image_data)?;
+    let original_filename = original.filename;
+    let original_tags = original.tags;
+    // The thumbnail goes away together with its image.
+    let btl = remaining_btl(state, original.expires_at_block).await?;
+
+    // --- 2. RESIZE ---
+    // Same rules as the Python sample: both dimensions give an exact resize,
+    // a single dimension keeps the original aspect ratio.
+    let (orig_width, orig_height) = (image_data.width(), image_data.height());
+    let (width, height, fit) = match (width, height) {
+        (Some(w), Some(h)) => (w, h, fit),
+        (Some(w), None) => (w, ((w as u64 * orig_height as u64) / orig_width as u64).max(1) as u32, FitMode::Exact),
+        (None, Some(h)) => (((h as u64 * orig_width as u64) / orig_height as u64).max(1) as u32, h, FitMode::Exact),
+        (None, None) => unreachable!(),
+    };
+    if !limits.contains(width) || !limits.contains(height) {
+        return Err(AppError::BadRequest(format!(
+            "Resulting size {}x{} is outside {}..={} pixels.",
+            width, height, limits.min, limits.max
+        )));
+    }
+    let rendition = Rendition { width, height, fit, encoding };
+    let resize = rendition.resize();
+    let rendered = {
+        let rendition = rendition.clone();
+        tokio::task::spawn_blocking(move || render(&image_data, &rendition))
+            .await
+            .map_err(|e| AppError::Internal(format!("Resize task failed: {}", e)))??
+    };
+    check_rendition_size(&state.config, &rendition, rendered.data.len())?;
+    let resized_bytes = Bytes::from(rendered.data);
+    println!("Resized image to {}x{} ({} bytes)", rendered.width, rendered.height, resized_bytes.len());
+
+    // --- 3. STORE AS A NEW THUMBNAIL ENTITY ---
+    let resized_create = Create {
+        data: resized_bytes.clone(),
+        btl,
+        string_annotations: vec![
+            Annotation::new("parent", entity_key.to_string()),
+            Annotation::new("type", "thumbnail"),
+            Annotation::new("app", state.config.app_tag.clone()),
+            Annotation::new("resize", resize.clone()),
+            Annotation::new("fit", fit.as_str()),
+            Annotation::new("filename", format!("{}_{}", resize, original_filename)),
+            Annotation::new("mime_type", encoding.format.mime_type()),
+        ]
+        .into_iter()
+        .chain(tag_annotations(&normalize_tags(original_tags.iter().map(String::as_str))))
+        .chain(identity.map(Identity::annotation))
+        .collect(),
+        numeric_annotations: vec![
+            Annotation::new("width", rendered.width as u64),
+            Annotation::new("height", rendered.height as u64),
+        ],
+    };
+    let receipts = state.client.create_entities(vec![resized_create]).await.map_err(AppError::rpc)?;
+    let resized_key = receipts[0].entity_key;
+    println!("Created resized entity: {:?}", resized_key);
+
+    Ok(StoredResize {
+        entity_key: resized_key,
+        data: resized_bytes,
+        resize,
+        mime_type: encoding.format.mime_type().to_string(),
+        expires_at_block: receipts[0].expiration_block,
+    })
+}
+
+// Handler for the `GET /image/:id/thumbnail?size=WxH` route.
+// Serves the thumbnail of the given size (the gallery thumbnail by default). If there is none yet,
+// it is generated from the original, with the preset's fit if the size is one of the configured
+// renditions (and JPEG otherwise), and stored until the image expires, so the next request finds it.
+// Missing sizes are only generated for images we own; other owners have to store them themselves.
+// Thumbnails never change once stored, so their entity key doubles as the ETag.
+// Only thumbnails stored by the image's owner are served (see `find_thumbnail`).
+async fn get_thumbnail(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    params: Result<axum::extract::Query<ThumbnailParams>, QueryRejection>,
+    identity: Option<Extension<Identity>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let axum::extract::Query(params) = params?;
+    let entity_key = parse_b256(&id)?;
+    let gallery_size = state.config.renditions[0].resize();
+    let size = params.size.as_deref().unwrap_or(&gallery_size);
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+        .ok_or_else(|| AppError::BadRequest(format!("size must look like 100x100, got {:?}.", size)))?;
+    let resize = format!("{}x{}", width, height);
+    println!("GET /image/{}/thumbnail called for size {}.", id, resize);
+
+    let parent = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
+    ensure_image(entity_key, &parent.string_annotations)?;
+    let existing = find_thumbnail(&state, entity_key, parent.owner, &resize).await?;
+
+    let (thumb_key, data, mime_type) = match existing {
+        Some(thumb_key) => {
+            let etag = format!("\"{}\"", thumb_key);
+            let if_none_match = headers.get(IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+            if if_none_match.is_some_and(|header| etag_matches(header, &etag)) {
+                return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+            }
+            let metadata = state.client.get_entity_metadata(thumb_key).await.map_err(AppError::rpc)?;
+            let mime_type = metadata
+                .string_annotations
+                .into_iter()
+                .find(|annot| annot.key == "mime_type")
+                .map(|annot| annot.value)
+                .unwrap_or_else(|| "image/jpeg".to_string());
+            let data = state.client.get_storage_value::<Vec<u8>>(thumb_key).await.map_err(AppError::rpc)?;
+            (thumb_key, Bytes::from(data), mime_type)
+        }
+        None => {
+            // Storing a new size costs the server's funds, so it is only done for authenticated callers.
+            let identity = identity.map(|Extension(identity)| identity);
+            if state.auth.is_enabled() && identity.is_none() {
+                return Err(AppError::Unauthorized(format!(
+                    "There is no {} thumbnail yet, and generating one needs an Authorization: Bearer token.",
+                    resize
+                )));
+            }
+            // We can only store thumbnails that belong to images we own.
+            ensure_owner(&state, entity_key, parent.owner)?;
+            println!("No {} thumbnail for {} yet, generating it.", resize, entity_key);
+            let (fit, encoding) = state
+                .config
+                .renditions
+                .iter()
+                .find(|rendition| rendition.width == width && rendition.height == height)
+                .map_or((FitMode::Exact, Encoding::default()), |rendition| (rendition.fit, rendition.encoding));
+            let resized = store_resized(&state, entity_key, Some(width), Some(height), fit, encoding, identity.as_ref()).await?;
+            (resized.entity_key, resized.data, resized.mime_type)
+        }
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", mime_type),
+            ("Content-Length", data.len().to_string()),
+            ("Cache-Control", format!("public, max-age={}", THUMBNAIL_MAX_AGE)),
+            ("ETag", format!("\"{}\"", thumb_key)),
+        ],
+        data,
+    ).into_response())
+}
+
+// Handler for the `POST /image/:id/extend` route.
+// Extends the main entity, its thumbnails and its chunks by the same number of blocks in a
+// single transaction, so the parts of an image never end up with different lifetimes.
+// Only images we own are extended (our wallet pays for it), together with their entities that
+// we own too, and the image may not end up living longer than `max_btl` blocks from now. With
+// authentication on, only the identity that uploaded the image can extend it.
+async fn extend_image(
+    State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    // This handler would receive width and height and add a new resized version of the image.
-    println!("POST /add-resize/{} called. This would resize the image.", id);
-    (StatusCode::OK, "Add-resize logic would go here.").into_response()
+    identity: Option<Extension<Identity>>,
+    request: Request,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let params: ExtendRequest = parse_body(request).await?;
+    if params.blocks == 0 {
+        return Err(AppError::BadRequest("blocks must be at least 1.".to_string()));
+    }
+    let entity_key = parse_b256(&id)?;
+    println!("POST /image/{}/extend by {} blocks.", id, params.blocks);
+
+    // Make sure the image exists before looking for its children.
+    let metadata = state.client.get_entity_metadata(entity_key).await.map_err(AppError::rpc)?;
+    ensure_owner(&state, entity_key, metadata.owner)?;
+    ensure_uploader(&state, entity_key, &metadata.string_annotations, identity.as_deref())?;
+    ensure_image(entity_key, &metadata.string_annotations)?;
+    let remaining = remaining_btl(&state, metadata.expires_at_block).await?;
+    if remaining.saturating_add(params.blocks) > state.config.max_btl {
+        return Err(AppError::BadRequest(format!(
+            "The image has {} blocks left; it can be extended by at most {} more.",
+            remaining,
+            state.config.max_btl.saturating_sub(remaining)
+        )));
+    }
+
+    let children = owned_children(&state, entity_key, metadata.owner, None).await?;
+
+    let extends: Vec<Extend> = std::iter::once(entity_key)
+        .chain(children)
+        .map(|key| Extend {
+            entity_key: key,
+            number_of_blocks: params.blocks,
+        })
+        .collect();
+    let receipts = state.client.extend_entities(extends).await.map_err(AppError::rpc)?;
+    println!("Extended {} entities.", receipts.len());
+
+    let expires_at_block = receipts.iter().map(|r| r.new_expiration_block).min().unwrap_or_default();
+    let entities: Vec<serde_json::Value> = receipts
+        .iter()
+        .map(|r| json!({
+            "entity_key": r.entity_key.to_string(),
+            "old_expiration_block": r.old_expiration_block,
+            "new_expiration_block": r.new_expiration_block,
+        }))
+        .collect();
+
+    Ok(Json(json!({
+        "message": "Image lifetime extended successfully!",
+        "entity_key": entity_key.to_string(),
+        "blocks": params.blocks,
+        "expires_at_block": expires_at_block,
+        "entities": entities,
+    })))
+}
+
+/// Helper function that works out how big a `POST /signed-uploads/:id` body can get: the
+/// transactions for the largest upload we accept, hex encoded (so twice their size). They carry
+/// every chunk, the first one again in the final update, and a rendition of up to `chunk_size`
+/// each.
+fn signed_transactions_body_limit(config: &Config) -> usize {
+    let renditions = config.renditions.len();
+    let transactions = config.max_upload_size.div_ceil(config.chunk_size) + renditions + 1;
+    let data = config.max_upload_size + config.chunk_size + renditions * config.chunk_size;
+    2 * (data + transactions * SIGNED_TRANSACTION_OVERHEAD)
+}
+
+/// Helper function that reads request parameters from either a JSON or a form-encoded body.
+async fn parse_body<T: DeserializeOwned>(request: Request) -> Result<T, AppError> {
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    if is_json {
+        Json::<T>::from_request(request, &())
+            .await
+            .map(|Json(params)| params)
+            .map_err(AppError::from)
+    } else {
+        Form::<T>::from_request(request, &())
+            .await
+            .map(|Form(params)| params)
+            .map_err(|e| AppError::BadRequest(e.body_text()))
+    }
 }
 
 // Handler for the `GET /query/:search` route.
 async fn query_entities(
     State(state): State<Arc<AppState>>,
     Path(search): Path<String>,
-) -> impl IntoResponse {
-    // The query string to search for thumbnails that match the tag.
-    let query = format!(
-        "type=\"thumbnail\" && app=\"golem-images-0.1\" && (tag~\"{}\" || (tag~\"{},*\" || (tag~\"*,{}\" || tag~\"*,{},\"))) ", 
-        search, search, search, search
-    );
+) -> Result<Json<Vec<String>>, AppError> {
+    // Every tag is its own annotation on the thumbnails, so an exact match finds exactly the tagged images.
+    // Only the gallery thumbnails are returned, one per image.
+    let query = Query::eq("type", "thumbnail")
+        .and(Query::eq("app", state.config.app_tag.as_str()))
+        .and(Query::eq("resize", state.config.renditions[0].resize()))
+        .and(Query::eq("tag", normalize_tag(&search)));
 
     println!("GET /query/{} called. Executing query: {}", search, query);
     
     // The Rust function already returns Vec<Hash>, so we map them to strings and return.
-    match state.client.query_entity_keys(&query).await {
-        Ok(keys) => Json(keys.into_iter().map(|key| key.to_string()).collect::<Vec<_>>()).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Query failed: {}", e)).into_response(),
-    }
-}
\ No newline at end of file
+    let keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+    Ok(Json(keys.into_iter().map(|key| key.to_string()).collect()))
+}
+// Handler for the `GET /search` route.
+// Finds the images matching all filters (see `SearchParams`) and returns one page of them,
+// each with its gallery thumbnail and metadata. Pass `next_cursor` back as `cursor` for the next page.
+async fn search_images(
+    State(state): State<Arc<AppState>>,
+    pairs: Result<axum::extract::Query<Vec<(String, String)>>, QueryRejection>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let axum::extract::Query(pairs) = pairs?;
+    let params = SearchParams::parse(pairs)?;
+    let query = params.to_query(&state.config.app_tag);
+    println!("GET /search called. Executing query: {}", query);
+
+    let keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+    image_page(&state, keys, &params).await
+}
+
+// Handler for the `GET /owners/:address/images` route.
+// The gallery of one wallet: the images owned by `address`, which are the ones it uploaded
+// through `/signed-uploads` (everything uploaded through `/upload` is owned by our wallet).
+// Takes the same filters and pagination as `/search`.
+async fn owner_images(
+    State(state): State<Arc<AppState>>,
+    Path(address): Path<String>,
+    pairs: Result<axum::extract::Query<Vec<(String, String)>>, QueryRejection>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let axum::extract::Query(pairs) = pairs?;
+    let owner: Address = address
+        .trim()
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("Invalid owner address: {}", address)))?;
+    let params = SearchParams::parse(pairs)?;
+    let query = params.to_query(&state.config.app_tag);
+    println!("GET /owners/{}/images called. Executing query: {}", owner, query);
+
+    // The query language can't filter by owner, so intersect with the owner's entities.
+    let owned: HashSet<Hash> = state
+        .client
+        .get_entities_of_owner(owner)
+        .await
+        .map_err(AppError::rpc)?
+        .into_iter()
+        .collect();
+    let mut keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+    keys.retain(|key| owned.contains(key));
+    image_page(&state, keys, &params).await
+}
+
+// Handler for the `GET /me/images` route.
+// The gallery of the authenticated caller: the images whose `uploader` is their identity,
+// whichever wallet owns them. Takes the same filters and pagination as `/search`.
+async fn my_images(
+    State(state): State<Arc<AppState>>,
+    identity: Option<Extension<Identity>>,
+    pairs: Result<axum::extract::Query<Vec<(String, String)>>, QueryRejection>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let axum::extract::Query(pairs) = pairs?;
+    let Some(Extension(identity)) = identity else {
+        return Err(AppError::Unauthorized("/me/images needs an Authorization: Bearer token.".to_string()));
+    };
+    let params = SearchParams::parse(pairs)?;
+    let query = params
+        .to_query(&state.config.app_tag)
+        .and(Query::eq("uploader", identity.0.as_str()));
+    println!("GET /me/images called. Executing query: {}", query);
+
+    let keys = state.client.query_entity_keys(&query.to_string()).await.map_err(AppError::rpc)?;
+    let mut page = image_page(&state, keys, &params).await?;
+    page["identity"] = json!(identity.0);
+    Ok(page)
+}
+
+/// Helper function that returns the page of the image `keys` that `params` asks for, each image
+/// with its gallery thumbnail and metadata.
+async fn image_page(state: &AppState, keys: Vec<Hash>, params: &SearchParams) -> Result<Json<serde_json::Value>, AppError> {
+    let total = keys.len();
+    let (page, next_cursor) = paginate(keys, params.cursor, params.limit);
+
+    let results: Vec<serde_json::Value> = stream::iter(page)
+        .map(|key| async move {
+            let metadata = state.client.get_entity_metadata(key).await.map_err(AppError::rpc)?;
+            let thumbnail = find_thumbnail(state, key, metadata.owner, &state.config.renditions[0].resize()).await?;
+
+            let string_annotation = |name: &str| {
+                metadata
+                    .string_annotations
+                    .iter()
+                    .find(|annot| annot.key == name)
+                    .map(|annot| annot.value.clone())
+            };
+            let numeric_annotation = |name: &str| {
+                metadata
+                    .numeric_annotations
+                    .iter()
+                    .find(|annot| annot.key == name)
+                    .map(|annot| annot.value)
+            };
+            Ok::<_, AppError>(json!({
+                "entity_key": key.to_string(),
+                "thumbnail": thumbnail.map(|key| key.to_string()),
+                "filename": string_annotation("filename"),
+                "mime_type": string_annotation("mime_type"),
+                "tags": stored_tags(&metadata.string_annotations),
+                "size": numeric_annotation("size"),
+                "width": numeric_annotation("width"),
+                "height": numeric_annotation("height"),
+                "uploaded_at": numeric_annotation("uploaded_at"),
+                "uploader": string_annotation("uploader"),
+                "owner": metadata.owner.to_string(),
+                "expires_at_block": metadata.expires_at_block,
+            }))
+        })
+        .buffered(state.config.chunk_fetch_concurrency)
+        .try_collect()
+        .await?;
+
+    Ok(Json(json!({
+        "results": results,
+        "total": total,
+        "next_cursor": next_cursor.map(|key| key.to_string()),
+    })))
+}
@@ -0,0 +1,108 @@
+use crate::upload::UploadError;
+use bytes::Bytes;
+use exif::{experimental::Writer, Field, In, Tag, Value};
+use golem_base_sdk::entity::Annotation;
+use img_parts::{jpeg::{markers, Jpeg}, png::Png, webp::WebP, ImageEXIF};
+use std::io::{BufRead, Cursor, Seek, SeekFrom};
+
+/// Keywords of PNG text chunks that carry XMP or (as written by ImageMagick) raw EXIF/XMP.
+const PNG_METADATA_KEYWORDS: &[&[u8]] = &[b"XML:com.adobe.xmp\0", b"Raw profile type"];
+
+/// Reads the EXIF fields we make searchable from the upload: `camera_make`, `camera_model`
+/// and `captured_at` (the original capture time as `YYYY-MM-DDTHH:MM:SS`, in the camera's
+/// local time). Files without EXIF, or with fields we can't read, simply yield fewer annotations.
+pub fn read_exif(file: &mut (impl BufRead + Seek)) -> Vec<Annotation<String>> {
+    let mut annotations = vec![];
+    if file.seek(SeekFrom::Start(0)).is_err() {
+        return annotations;
+    }
+    let Ok(exif) = exif::Reader::new().read_from_container(file) else {
+        return annotations;
+    };
+
+    let ascii = |tag: Tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    };
+
+    if let Some(make) = ascii(Tag::Make) {
+        annotations.push(Annotation::new("camera_make", make));
+    }
+    if let Some(model) = ascii(Tag::Model) {
+        annotations.push(Annotation::new("camera_model", model));
+    }
+    let captured_at = ascii(Tag::DateTimeOriginal)
+        .or_else(|| ascii(Tag::DateTime))
+        .and_then(|value| exif::DateTime::from_ascii(value.as_bytes()).ok());
+    if let Some(t) = captured_at {
+        annotations.push(Annotation::new(
+            "captured_at",
+            format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", t.year, t.month, t.day, t.hour, t.minute, t.second),
+        ));
+    }
+    annotations
+}
+
+/// Removes EXIF and XMP metadata (camera details, GPS coordinates, ...) from a JPEG, PNG or
+/// WebP file without re-encoding it. The pixels, and colour profiles, are left untouched.
+/// The EXIF orientation is the one thing we put back, in an otherwise empty EXIF block,
+/// so the stored original is still displayed the right way up.
+pub fn strip_metadata(data: Bytes, mime_type: &str) -> Result<Bytes, UploadError> {
+    let stripped = match mime_type {
+        "image/jpeg" => {
+            let mut jpeg = Jpeg::from_bytes(data)?;
+            let orientation = orientation_only(jpeg.exif());
+            // Both EXIF and XMP live in APP1 segments.
+            jpeg.remove_segments_by_marker(markers::APP1);
+            // `set_exif` inserts the segment after the first three, which any decodable JPEG has.
+            if orientation.is_some() && jpeg.segments().len() >= 3 {
+                jpeg.set_exif(orientation);
+            }
+            jpeg.encoder().bytes()
+        }
+        "image/png" => {
+            let mut png = Png::from_bytes(data)?;
+            let orientation = orientation_only(png.exif());
+            png.set_exif(orientation);
+            png.chunks_mut().retain(|chunk| {
+                !matches!(&chunk.kind(), b"iTXt" | b"tEXt" | b"zTXt")
+                    || !PNG_METADATA_KEYWORDS.iter().any(|keyword| chunk.contents().starts_with(keyword))
+            });
+            png.encoder().bytes()
+        }
+        "image/webp" => {
+            let mut webp = WebP::from_bytes(data)?;
+            let orientation = orientation_only(webp.exif());
+            webp.remove_chunks_by_id(*b"XMP ");
+            // Also updates the VP8X header flags to match the remaining chunks.
+            webp.set_exif(orientation);
+            webp.encoder().bytes()
+        }
+        _ => data,
+    };
+    Ok(stripped)
+}
+
+/// Builds an EXIF block holding nothing but the orientation of `exif`, or `None` if it has
+/// no orientation other than the default.
+fn orientation_only(exif: Option<Bytes>) -> Option<Bytes> {
+    let exif = exif::Reader::new().read_raw(exif?.to_vec()).ok()?;
+    let field = exif.get_field(Tag::Orientation, In::PRIMARY)?;
+    if field.value.get_uint(0).is_none_or(|orientation| orientation == 1) {
+        return None;
+    }
+
+    let field = Field {
+        tag: Tag::Orientation,
+        ifd_num: In::PRIMARY,
+        value: field.value.clone(),
+    };
+    let mut writer = Writer::new();
+    writer.push_field(&field);
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, exif.little_endian()).ok()?;
+    Some(out.into_inner().into())
+}
@@ -0,0 +1,82 @@
+use crate::{
+    query::Query,
+    remaining_btl,
+    tags::{normalize_tags, stored_tags, tag_annotations},
+    AppState,
+};
+use golem_base_sdk::{entity::Update, Hash};
+
+/// Rewrites the tags of every image and thumbnail we own into the normalized model:
+/// one lower-cased `tag` annotation per tag instead of a single comma-joined one.
+/// Thumbnails get the tags of their parent image, so `/query/:search` finds them.
+///
+/// Entities are updated one at a time, keeping their data, other annotations and expiry,
+/// so the migration can be interrupted and run again. With `dry_run` nothing is written.
+pub async fn migrate_tags(state: &AppState, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let query = Query::eq("app", state.config.app_tag.as_str())
+        .and(Query::any([Query::eq("type", "image"), Query::eq("type", "thumbnail")]));
+    println!("Migrating tags. Executing query: {}", query);
+    let keys = state.client.query_entity_keys(&query.to_string()).await?;
+    let signer = state.client.get_owner_address();
+
+    let (mut migrated, mut skipped) = (0, 0);
+    for key in keys {
+        let metadata = state.client.get_entity_metadata(key).await?;
+        if metadata.owner != signer {
+            skipped += 1;
+            continue;
+        }
+
+        let old_tags = stored_tags(&metadata.string_annotations);
+        let parent = metadata
+            .string_annotations
+            .iter()
+            .find(|annot| annot.key == "parent")
+            .and_then(|annot| annot.value.parse::<Hash>().ok());
+        let source_tags = match parent {
+            Some(parent) => match state.client.get_entity_metadata(parent).await {
+                Ok(parent) => stored_tags(&parent.string_annotations),
+                // The parent is gone; leave the orphaned thumbnail alone.
+                Err(_) => old_tags.clone(),
+            },
+            None => old_tags.clone(),
+        };
+        let new_tags = normalize_tags(source_tags.iter().map(String::as_str));
+        if old_tags == new_tags {
+            continue;
+        }
+        println!("{}: {:?} -> {:?}", key, old_tags, new_tags);
+        if dry_run {
+            migrated += 1;
+            continue;
+        }
+
+        let mut string_annotations: Vec<_> = metadata
+            .string_annotations
+            .into_iter()
+            .filter(|annot| annot.key != "tag")
+            .collect();
+        string_annotations.extend(tag_annotations(&new_tags));
+
+        let data = state.client.get_storage_value::<Vec<u8>>(key).await?;
+        state
+            .client
+            .update_entities(vec![Update {
+                entity_key: key,
+                data: data.into(),
+                btl: remaining_btl(state, metadata.expires_at_block).await?,
+                string_annotations,
+                numeric_annotations: metadata.numeric_annotations,
+            }])
+            .await?;
+        migrated += 1;
+    }
+
+    println!(
+        "{} {} entities, skipped {} owned by someone else.",
+        if dry_run { "Would migrate" } else { "Migrated" },
+        migrated,
+        skipped
+    );
+    Ok(())
+}
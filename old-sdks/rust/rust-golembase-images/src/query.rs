@@ -0,0 +1,273 @@
+use std::fmt;
+
+/// A Golem Base query, built from typed parts instead of `format!` so that user input can
+/// never change its structure. Render it with `to_string()` and pass the result to
+/// `query_entity_keys` / `query_entities`.
+///
+/// String values are always emitted as quoted, escaped literals, and `and`/`or` put their
+/// operands in parentheses, so a value like `x" || app="other` stays a plain value.
+///
+/// ```ignore
+/// let query = Query::eq("type", "thumbnail").and(Query::eq("app", app_tag));
+/// // type="thumbnail" && app="golem-images-0.1"
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    /// `key = "value"`
+    Eq(String, String),
+    /// `key ~ "pattern"`, where `*`, `?` and `[...]` in `pattern` are glob wildcards.
+    Glob(String, String),
+    /// `key <op> number` on a numeric annotation.
+    Numeric(String, Cmp, u64),
+    /// All of the queries must match.
+    And(Vec<Query>),
+    /// Any of the queries must match.
+    Or(Vec<Query>),
+}
+
+/// Comparison operators for numeric annotations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cmp {
+    Le,
+    Ge,
+}
+
+impl Cmp {
+    fn as_str(self) -> &'static str {
+        match self {
+            Cmp::Le => "<=",
+            Cmp::Ge => ">=",
+        }
+    }
+}
+
+/// Whether `key` can be used as an annotation key in a query: a letter or `_` followed by
+/// letters, digits and `_`. Keys taken from user input must be checked with this first.
+pub fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Escapes `value` so it can be placed between double quotes in a query.
+pub fn escape_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes the glob wildcards in `value`, so it only matches itself inside a `glob` pattern.
+pub fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn checked_key(key: impl Into<String>) -> String {
+    let key = key.into();
+    assert!(is_valid_key(&key), "invalid annotation key in query: {:?}", key);
+    key
+}
+
+impl Query {
+    /// `key = "value"`. Panics if `key` isn't a valid annotation key (see `is_valid_key`).
+    pub fn eq(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Query::Eq(checked_key(key), value.into())
+    }
+
+    /// `key ~ "pattern"`. Use `escape_glob` on the parts of `pattern` that come from users.
+    pub fn glob(key: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Query::Glob(checked_key(key), pattern.into())
+    }
+
+    /// `key <cmp> value` on a numeric annotation.
+    pub fn numeric(key: impl Into<String>, cmp: Cmp, value: u64) -> Self {
+        Query::Numeric(checked_key(key), cmp, value)
+    }
+
+    pub fn le(key: impl Into<String>, value: u64) -> Self {
+        Query::numeric(key, Cmp::Le, value)
+    }
+
+    pub fn ge(key: impl Into<String>, value: u64) -> Self {
+        Query::numeric(key, Cmp::Ge, value)
+    }
+
+    /// All of `queries`. Panics if there are none, since the query language has no "true".
+    pub fn all(queries: impl IntoIterator<Item = Query>) -> Self {
+        let mut queries: Vec<Query> = queries.into_iter().collect();
+        assert!(!queries.is_empty(), "Query::all needs at least one query");
+        if queries.len() == 1 {
+            return queries.remove(0);
+        }
+        Query::And(queries)
+    }
+
+    /// Any of `queries`. Panics if there are none, since the query language has no "false".
+    pub fn any(queries: impl IntoIterator<Item = Query>) -> Self {
+        let mut queries: Vec<Query> = queries.into_iter().collect();
+        assert!(!queries.is_empty(), "Query::any needs at least one query");
+        if queries.len() == 1 {
+            return queries.remove(0);
+        }
+        Query::Or(queries)
+    }
+
+    /// `self && other`, flattening chains of `and`.
+    pub fn and(self, other: Query) -> Self {
+        match self {
+            Query::And(mut queries) => {
+                queries.push(other);
+                Query::And(queries)
+            }
+            query => Query::And(vec![query, other]),
+        }
+    }
+
+    /// `self || other`, flattening chains of `or`.
+    pub fn or(self, other: Query) -> Self {
+        match self {
+            Query::Or(mut queries) => {
+                queries.push(other);
+                Query::Or(queries)
+            }
+            query => Query::Or(vec![query, other]),
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::And(_) | Query::Or(_) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Eq(key, value) => write!(f, "{}=\"{}\"", key, escape_string(value)),
+            Query::Glob(key, pattern) => write!(f, "{}~\"{}\"", key, escape_string(pattern)),
+            Query::Numeric(key, cmp, value) => write!(f, "{}{}{}", key, cmp.as_str(), value),
+            Query::And(queries) | Query::Or(queries) => {
+                let separator = if matches!(self, Query::And(_)) { " && " } else { " || " };
+                for (i, query) in queries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(separator)?;
+                    }
+                    query.fmt_operand(f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_simple_comparisons() {
+        assert_eq!(Query::eq("type", "image").to_string(), r#"type="image""#);
+        assert_eq!(Query::glob("filename", "*.png").to_string(), r#"filename~"*.png""#);
+        assert_eq!(Query::ge("part", 2).to_string(), "part>=2");
+        assert_eq!(Query::le("size", 100).to_string(), "size<=100");
+    }
+
+    #[test]
+    fn groups_nested_operands() {
+        let query = Query::eq("app", "a")
+            .and(Query::eq("type", "image").or(Query::eq("type", "thumbnail")))
+            .and(Query::le("part", 3));
+        assert_eq!(
+            query.to_string(),
+            r#"app="a" && (type="image" || type="thumbnail") && part<=3"#
+        );
+    }
+
+    #[test]
+    fn all_and_any_collapse_single_queries() {
+        assert_eq!(Query::all([Query::eq("a", "1")]), Query::eq("a", "1"));
+        assert_eq!(
+            Query::any([Query::eq("a", "1"), Query::eq("b", "2")]).to_string(),
+            r#"a="1" || b="2""#
+        );
+    }
+
+    #[test]
+    fn quotes_cannot_break_out_of_a_value() {
+        let query = Query::eq("tag", r#"x" || app="other"#).and(Query::eq("app", "mine"));
+        assert_eq!(
+            query.to_string(),
+            r#"tag="x\" || app=\"other" && app="mine""#
+        );
+    }
+
+    #[test]
+    fn backslashes_cannot_escape_the_closing_quote() {
+        assert_eq!(Query::eq("tag", r"x\").to_string(), r#"tag="x\\""#);
+        assert_eq!(
+            Query::eq("tag", r#"x\" || type="image"#).to_string(),
+            r#"tag="x\\\" || type=\"image""#
+        );
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(Query::eq("tag", "a\nb\tc\r").to_string(), r#"tag="a\nb\tc\r""#);
+    }
+
+    #[test]
+    fn operators_and_parentheses_in_values_are_literal() {
+        assert_eq!(
+            Query::eq("tag", "a) || (b && c").to_string(),
+            r#"tag="a) || (b && c""#
+        );
+    }
+
+    #[test]
+    fn unicode_values_are_kept() {
+        assert_eq!(Query::eq("tag", "zażółć 🌅").to_string(), "tag=\"zażółć 🌅\"");
+    }
+
+    #[test]
+    fn glob_wildcards_can_be_escaped() {
+        let pattern = format!("{}*", escape_glob("a*b?[c]\\"));
+        assert_eq!(
+            Query::glob("filename", pattern).to_string(),
+            r#"filename~"a\\*b\\?\\[c\\]\\\\*""#
+        );
+    }
+
+    #[test]
+    fn validates_keys() {
+        assert!(is_valid_key("custom_key1"));
+        assert!(is_valid_key("_x"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("1abc"));
+        assert!(!is_valid_key("a b"));
+        assert!(!is_valid_key("a=\"b\""));
+        assert!(!is_valid_key("a||b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid annotation key")]
+    fn rejects_hostile_keys() {
+        Query::eq(r#"type="image" || tag"#, "x");
+    }
+}
@@ -0,0 +1,241 @@
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage,
+};
+use std::{fmt, str::FromStr};
+
+/// The quality used for lossy formats when a rendition doesn't set one.
+pub const DEFAULT_QUALITY: u8 = 85;
+
+/// How an image is fitted into a rendition's `width`x`height` box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitMode {
+    /// Fill the box exactly, cropping what sticks out (the gallery thumbnails).
+    Crop,
+    /// Scale down to fit inside the box, keeping the aspect ratio. Smaller images are left as they are.
+    Fit,
+    /// Stretch to exactly the box, ignoring the aspect ratio (`/add-resize/:id`).
+    Exact,
+}
+
+impl FitMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FitMode::Crop => "crop",
+            FitMode::Fit => "fit",
+            FitMode::Exact => "exact",
+        }
+    }
+}
+
+impl FromStr for FitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crop" => Ok(FitMode::Crop),
+            "fit" => Ok(FitMode::Fit),
+            "exact" => Ok(FitMode::Exact),
+            _ => Err(format!("unknown fit {:?}, expected crop, fit or exact", s)),
+        }
+    }
+}
+
+/// The format a rendition is encoded in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Jpeg,
+    /// Lossless, and keeps the alpha channel.
+    Png,
+    /// Lossless (the encoder doesn't support lossy WebP), and keeps the alpha channel.
+    WebP,
+    /// Only available when built with the `avif` feature.
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    /// The value stored in the `mime_type` annotation.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::WebP),
+            "avif" if cfg!(feature = "avif") => Ok(OutputFormat::Avif),
+            "avif" => Err("avif output needs the server to be built with the avif feature".to_string()),
+            _ => Err(format!("unknown format {:?}, expected jpeg, png, webp or avif", s)),
+        }
+    }
+}
+
+/// How a rendition is encoded. `quality` (1-100) applies to JPEG and AVIF and defaults to
+/// `DEFAULT_QUALITY`; PNG and WebP are always lossless and don't take one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Encoding {
+    pub format: OutputFormat,
+    pub quality: Option<u8>,
+}
+
+impl Encoding {
+    pub fn new(format: OutputFormat, quality: Option<u8>) -> Result<Self, String> {
+        if quality.is_some_and(|quality| !(1..=100).contains(&quality)) {
+            return Err("quality must be between 1 and 100".to_string());
+        }
+        if quality.is_some() && matches!(format, OutputFormat::Png | OutputFormat::WebP) {
+            return Err(format!("{} is lossless and doesn't take a quality", format.as_str()));
+        }
+        Ok(Encoding { format, quality })
+    }
+
+    fn quality(&self) -> u8 {
+        self.quality.unwrap_or(DEFAULT_QUALITY)
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            format: OutputFormat::Jpeg,
+            quality: None,
+        }
+    }
+}
+
+/// A thumbnail preset, written as `WIDTHxHEIGHT:FIT[:FORMAT[:QUALITY]]` (e.g. `100x100:crop`,
+/// `400x400:fit:webp`, `1200x1200:fit:jpeg:70`) in the config file and on the command line.
+/// Without a format, renditions are JPEG.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+    pub fit: FitMode,
+    pub encoding: Encoding,
+}
+
+impl Rendition {
+    /// The value of the `resize` annotation: the preset's box, which for `fit` can be larger
+    /// than the stored image (its real size is in the `width`/`height` annotations).
+    pub fn resize(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Rendition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split(':');
+        let size = parts.next().unwrap_or_default();
+        let (width, height) = size
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+            .filter(|(w, h)| *w > 0 && *h > 0)
+            .ok_or_else(|| format!("invalid rendition {:?}, expected e.g. 400x400:fit", s))?;
+        let fit = parts.next().unwrap_or("fit").parse()?;
+        let format = parts.next().map_or(Ok(OutputFormat::Jpeg), str::parse)?;
+        let quality = parts
+            .next()
+            .map(|quality| quality.parse::<u8>().map_err(|_| format!("invalid quality {:?}", quality)))
+            .transpose()?;
+        if parts.next().is_some() {
+            return Err(format!("invalid rendition {:?}, expected WIDTHxHEIGHT:FIT[:FORMAT[:QUALITY]]", s));
+        }
+        Ok(Rendition {
+            width,
+            height,
+            fit,
+            encoding: Encoding::new(format, quality)?,
+        })
+    }
+}
+
+impl fmt::Display for Rendition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}:{}:{}", self.width, self.height, self.fit.as_str(), self.encoding.format.as_str())?;
+        if let Some(quality) = self.encoding.quality {
+            write!(f, ":{}", quality)?;
+        }
+        Ok(())
+    }
+}
+
+/// An encoded rendition and the size it ended up with.
+pub struct Rendered {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Resizes `image` according to `rendition` and encodes it in the rendition's format.
+/// CPU-bound, so call it from a blocking thread.
+pub fn render(image: &DynamicImage, rendition: &Rendition) -> Result<Rendered, image::ImageError> {
+    let resized = match rendition.fit {
+        FitMode::Crop => image.resize_to_fill(rendition.width, rendition.height, FilterType::Lanczos3),
+        FitMode::Fit if image.width() <= rendition.width && image.height() <= rendition.height => image.clone(),
+        FitMode::Fit => image.resize(rendition.width, rendition.height, FilterType::Lanczos3),
+        FitMode::Exact => image.resize_exact(rendition.width, rendition.height, FilterType::Lanczos3),
+    };
+
+    Ok(Rendered {
+        data: encode(&resized, rendition.encoding)?,
+        width: resized.width(),
+        height: resized.height(),
+    })
+}
+
+/// Encodes `image` in memory. JPEG has no alpha channel, so transparent images are flattened
+/// to RGB for it; the other formats get 8-bit RGBA so transparency is kept.
+pub fn encode(image: &DynamicImage, encoding: Encoding) -> Result<Vec<u8>, image::ImageError> {
+    let mut data = Vec::new();
+    match encoding.format {
+        OutputFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut data, encoding.quality());
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        OutputFormat::Png => {
+            image.write_with_encoder(PngEncoder::new(&mut data))?;
+        }
+        OutputFormat::WebP => {
+            let encoder = WebPEncoder::new_lossless(&mut data);
+            DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)?;
+        }
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => {
+            // Speed 6 is a reasonable trade-off between encoding time and size for thumbnails.
+            let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut data, 6, encoding.quality());
+            DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)?;
+        }
+        #[cfg(not(feature = "avif"))]
+        OutputFormat::Avif => {
+            return Err(image::ImageError::Unsupported(
+                image::error::UnsupportedError::from_format_and_kind(
+                    image::error::ImageFormatHint::Exact(image::ImageFormat::Avif),
+                    image::error::UnsupportedErrorKind::Format(image::error::ImageFormatHint::Exact(image::ImageFormat::Avif)),
+                ),
+            ));
+        }
+    }
+    Ok(data)
+}
@@ -0,0 +1,229 @@
+use crate::{
+    error::AppError,
+    parse_b256,
+    query::{escape_glob, Query},
+    tags::normalize_tag,
+};
+use golem_base_sdk::Hash;
+
+/// How many results `GET /search` returns when no `limit` is given, and the most it returns at once.
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// The filters of a `GET /search` request.
+///
+/// `tag` and `mime` may be repeated. Tags must all match unless `tag_mode=any` is given;
+/// a repeated `mime` matches any of the listed types. All other filters are combined with AND.
+/// `filename` is a glob pattern (`*`, `?`, `[...]`) matched against the image's filename.
+/// `uploaded_after` / `uploaded_before` are Unix timestamps in seconds, and
+/// `min_size` / `max_size` are in bytes; all four are inclusive.
+/// `camera_make` and `camera_model` match the EXIF values exactly, and `captured` matches
+/// images whose EXIF capture time starts with it, e.g. `2024` or `2024-06-01`.
+#[derive(Default)]
+pub struct SearchParams {
+    pub tags: Vec<String>,
+    pub match_any_tag: bool,
+    pub filename: Option<String>,
+    pub mime_types: Vec<String>,
+    pub uploaded_after: Option<u64>,
+    pub uploaded_before: Option<u64>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub captured: Option<String>,
+    pub limit: usize,
+    /// The last key of the previous page; results continue after it.
+    pub cursor: Option<Hash>,
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, AppError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("{} must be a non-negative integer, got {:?}.", name, value)))
+}
+
+impl SearchParams {
+    /// Reads the filters from the query string pairs, in which keys may repeat.
+    pub fn parse(pairs: Vec<(String, String)>) -> Result<Self, AppError> {
+        let mut params = SearchParams {
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        for (name, value) in pairs {
+            match name.as_str() {
+                "tag" => {
+                    let tag = normalize_tag(&value);
+                    if !tag.is_empty() && !params.tags.contains(&tag) {
+                        params.tags.push(tag);
+                    }
+                }
+                "tag_mode" => {
+                    params.match_any_tag = match value.as_str() {
+                        "all" => false,
+                        "any" => true,
+                        _ => return Err(AppError::BadRequest(format!("tag_mode must be \"all\" or \"any\", got {:?}.", value))),
+                    }
+                }
+                "filename" if !value.is_empty() => params.filename = Some(value),
+                "mime" if !value.is_empty() => params.mime_types.push(value),
+                "uploaded_after" => params.uploaded_after = Some(parse_number(&name, &value)?),
+                "uploaded_before" => params.uploaded_before = Some(parse_number(&name, &value)?),
+                "min_size" => params.min_size = Some(parse_number(&name, &value)?),
+                "max_size" => params.max_size = Some(parse_number(&name, &value)?),
+                "camera_make" if !value.is_empty() => params.camera_make = Some(value),
+                "camera_model" if !value.is_empty() => params.camera_model = Some(value),
+                "captured" if !value.is_empty() => params.captured = Some(value),
+                "limit" => {
+                    let limit = parse_number(&name, &value)? as usize;
+                    if !(1..=MAX_LIMIT).contains(&limit) {
+                        return Err(AppError::BadRequest(format!("limit must be between 1 and {}.", MAX_LIMIT)));
+                    }
+                    params.limit = limit;
+                }
+                "cursor" if !value.is_empty() => params.cursor = Some(parse_b256(&value)?),
+                _ => {
+                    // Ignore other parameters
+                }
+            }
+        }
+        Ok(params)
+    }
+
+    /// Builds the query for the images matching these filters.
+    pub fn to_query(&self, app_tag: &str) -> Query {
+        let mut query = Query::eq("type", "image").and(Query::eq("app", app_tag));
+
+        if !self.tags.is_empty() {
+            let tags = self.tags.iter().map(|tag| Query::eq("tag", tag.as_str()));
+            query = query.and(if self.match_any_tag { Query::any(tags) } else { Query::all(tags) });
+        }
+        if let Some(filename) = &self.filename {
+            query = query.and(Query::glob("filename", filename.as_str()));
+        }
+        if !self.mime_types.is_empty() {
+            query = query.and(Query::any(self.mime_types.iter().map(|mime| Query::eq("mime_type", mime.as_str()))));
+        }
+        if let Some(after) = self.uploaded_after {
+            query = query.and(Query::ge("uploaded_at", after));
+        }
+        if let Some(before) = self.uploaded_before {
+            query = query.and(Query::le("uploaded_at", before));
+        }
+        if let Some(min) = self.min_size {
+            query = query.and(Query::ge("size", min));
+        }
+        if let Some(max) = self.max_size {
+            query = query.and(Query::le("size", max));
+        }
+        if let Some(make) = &self.camera_make {
+            query = query.and(Query::eq("camera_make", make.as_str()));
+        }
+        if let Some(model) = &self.camera_model {
+            query = query.and(Query::eq("camera_model", model.as_str()));
+        }
+        if let Some(captured) = &self.captured {
+            query = query.and(Query::glob("captured_at", format!("{}*", escape_glob(captured))));
+        }
+        query
+    }
+}
+
+/// Picks the page of `keys` that follows `cursor`. Keys are sorted first, so a cursor stays
+/// valid while images are added or removed: the next page simply starts after the last key
+/// that was returned. Also returns the cursor of the following page, if there is one.
+pub fn paginate(mut keys: Vec<Hash>, cursor: Option<Hash>, limit: usize) -> (Vec<Hash>, Option<Hash>) {
+    keys.sort();
+    keys.dedup();
+    let start = cursor.map_or(0, |cursor| keys.partition_point(|key| *key <= cursor));
+    let end = (start + limit).min(keys.len());
+    let next_cursor = (end < keys.len()).then(|| keys[end - 1]);
+    (keys[start..end].to_vec(), next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> Hash {
+        Hash::with_last_byte(n)
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn pages_through_sorted_keys() {
+        let keys = vec![key(3), key(1), key(5), key(2), key(4), key(1)];
+        let (page, cursor) = paginate(keys.clone(), None, 2);
+        assert_eq!(page, vec![key(1), key(2)]);
+        assert_eq!(cursor, Some(key(2)));
+
+        let (page, cursor) = paginate(keys.clone(), cursor, 2);
+        assert_eq!(page, vec![key(3), key(4)]);
+        assert_eq!(cursor, Some(key(4)));
+
+        let (page, cursor) = paginate(keys, cursor, 2);
+        assert_eq!(page, vec![key(5)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn has_no_next_page_when_the_last_one_is_full() {
+        let (page, cursor) = paginate(vec![key(1), key(2)], None, 2);
+        assert_eq!(page, vec![key(1), key(2)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn continues_after_a_cursor_that_is_gone() {
+        // The image the cursor points at was deleted in the meantime.
+        let (page, cursor) = paginate(vec![key(1), key(3), key(4)], Some(key(2)), 10);
+        assert_eq!(page, vec![key(3), key(4)]);
+        assert_eq!(cursor, None);
+
+        let (page, cursor) = paginate(vec![key(1), key(3)], Some(key(9)), 10);
+        assert!(page.is_empty());
+        assert_eq!(cursor, None);
+
+        let (page, cursor) = paginate(vec![], None, 10);
+        assert!(page.is_empty());
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn checks_the_limit() {
+        assert_eq!(SearchParams::parse(vec![]).unwrap().limit, DEFAULT_LIMIT);
+        assert_eq!(SearchParams::parse(pairs(&[("limit", "1")])).unwrap().limit, 1);
+        assert_eq!(SearchParams::parse(pairs(&[("limit", "100")])).unwrap().limit, MAX_LIMIT);
+        for limit in ["0", "101", "-1", "ten"] {
+            assert!(matches!(
+                SearchParams::parse(pairs(&[("limit", limit)])),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_cursors_and_repeated_filters() {
+        let params = SearchParams::parse(pairs(&[
+            ("tag", " Sunset "),
+            ("tag", "sunset"),
+            ("tag", "beach"),
+            ("tag_mode", "any"),
+            ("mime", "image/png"),
+            ("cursor", &key(7).to_string()),
+            ("unknown", "ignored"),
+        ]))
+        .unwrap();
+        assert_eq!(params.tags, vec!["sunset", "beach"]);
+        assert!(params.match_any_tag);
+        assert_eq!(params.mime_types, vec!["image/png"]);
+        assert_eq!(params.cursor, Some(key(7)));
+
+        assert!(SearchParams::parse(pairs(&[("cursor", "nope")])).is_err());
+        assert!(SearchParams::parse(pairs(&[("tag_mode", "some")])).is_err());
+    }
+}
@@ -0,0 +1,605 @@
+use crate::{auth::Identity, error::AppError, rendition::Rendered};
+use alloy_consensus::{transaction::SignerRecoverable, Transaction, TxEnvelope};
+use alloy_eips::eip2718::Decodable2718;
+use alloy_primitives::{address, keccak256, Address, B256, U256};
+use alloy_rlp::Header;
+use bytes::Bytes;
+use golem_base_sdk::{
+    entity::{Annotation, Create, Update},
+    Hash, Url,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Golem Base storage transactions are sent to this address, with the RLP encoded
+/// operations as their input.
+pub const STORAGE_ADDRESS: Address = address!("0x0000000000000000000000000000000060138453");
+
+/// The events the storage processor logs for every change to an entity. All of them carry the
+/// entity key as their first topic. Except for deletions, the block at which the entity expires
+/// is the last word of their data; an extension logs the old expiry before it.
+/// Expired entities are removed with a deletion log too.
+const ENTITY_CREATED_EVENT: &str = "GolemBaseStorageEntityCreated(uint256,uint256)";
+const ENTITY_UPDATED_EVENT: &str = "GolemBaseStorageEntityUpdated(uint256,uint256)";
+const ENTITY_DELETED_EVENT: &str = "GolemBaseStorageEntityDeleted(uint256)";
+const ENTITY_EXTENDED_EVENT: &str = "GolemBaseStorageEntityBTLExtended(uint256,uint256,uint256)";
+
+/// The most blocks asked for in one `eth_getLogs` call, which nodes limit.
+const MAX_LOG_RANGE: u64 = 1000;
+
+/// How long a prepared upload waits for its signed transactions, how many may wait at once,
+/// and how many bytes of images and thumbnails they may hold together.
+const PENDING_TTL: Duration = Duration::from_secs(15 * 60);
+const MAX_PENDING_UPLOADS: usize = 64;
+const MAX_PENDING_BYTES: usize = 256 * 1024 * 1024;
+
+/// How long we wait for a relayed transaction to be included in a block.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(120);
+const RECEIPT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Encodes a storage transaction the way the Golem Base node expects it:
+/// `[creates, updates, deletes, extensions]`, where a create is
+/// `[btl, data, string_annotations, numeric_annotations]`, an update is the same prefixed with
+/// its entity key, and every annotation is a `[key, value]` pair. We never delete or extend here.
+pub fn encode_transaction(creates: &[Create], updates: &[Update]) -> Bytes {
+    let creates: Vec<Vec<u8>> = creates
+        .iter()
+        .map(|create| {
+            list(&[
+                alloy_rlp::encode(create.btl),
+                alloy_rlp::encode(&create.data[..]),
+                annotations(&create.string_annotations, |value| alloy_rlp::encode(value.as_str())),
+                annotations(&create.numeric_annotations, |value| alloy_rlp::encode(*value)),
+            ])
+        })
+        .collect();
+    let updates: Vec<Vec<u8>> = updates
+        .iter()
+        .map(|update| {
+            list(&[
+                alloy_rlp::encode(update.entity_key.as_slice()),
+                alloy_rlp::encode(update.btl),
+                alloy_rlp::encode(&update.data[..]),
+                annotations(&update.string_annotations, |value| alloy_rlp::encode(value.as_str())),
+                annotations(&update.numeric_annotations, |value| alloy_rlp::encode(*value)),
+            ])
+        })
+        .collect();
+    list(&[list(&creates), list(&updates), list(&[]), list(&[])]).into()
+}
+
+fn annotations<T>(annotations: &[Annotation<T>], encode_value: impl Fn(&T) -> Vec<u8>) -> Vec<u8> {
+    let pairs: Vec<Vec<u8>> = annotations
+        .iter()
+        .map(|annotation| list(&[alloy_rlp::encode(annotation.key.as_str()), encode_value(&annotation.value)]))
+        .collect();
+    list(&pairs)
+}
+
+/// An RLP list of already encoded items.
+fn list(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    Header {
+        list: true,
+        payload_length: items.iter().map(Vec::len).sum(),
+    }
+    .encode(&mut out);
+    for item in items {
+        out.extend_from_slice(item);
+    }
+    out
+}
+
+/// Checks that `raw` is a transaction signed by `owner` for `chain_id` whose input hashes to
+/// `expected`, the hash of the storage operations we prepared, so we never relay anything else.
+pub fn check_signed(raw: &[u8], owner: Address, chain_id: u64, expected: B256) -> Result<(), AppError> {
+    let tx = TxEnvelope::decode_2718(&mut &raw[..])
+        .map_err(|e| AppError::BadRequest(format!("Not a signed Ethereum transaction: {}", e)))?;
+    let signer = tx
+        .recover_signer()
+        .map_err(|e| AppError::BadRequest(format!("Invalid transaction signature: {}", e)))?;
+    if signer != owner {
+        return Err(AppError::Forbidden(format!("The transaction is signed by {}, not by the owner {}.", signer, owner)));
+    }
+    if tx.chain_id() != Some(chain_id) {
+        return Err(AppError::BadRequest(format!("The transaction must be signed for chain {}.", chain_id)));
+    }
+    if tx.to() != Some(STORAGE_ADDRESS) || !tx.value().is_zero() || keccak256(tx.input()) != expected {
+        return Err(AppError::BadRequest("The transaction doesn't match the one that was prepared.".to_string()));
+    }
+    Ok(())
+}
+
+/// An entity created or updated by a relayed transaction.
+#[derive(Clone, Copy, Debug)]
+pub struct Stored {
+    pub entity_key: Hash,
+    pub expires_at_block: u64,
+}
+
+/// What happened to an entity, according to a storage log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageChange {
+    Created,
+    Updated,
+    Deleted,
+    Extended,
+}
+
+/// A storage log of the node.
+#[derive(Clone, Copy, Debug)]
+pub struct StorageLog {
+    pub change: StorageChange,
+    pub entity_key: Hash,
+    /// The block at which the entity expires now; not known for deletions.
+    pub expires_at_block: Option<u64>,
+    /// The block the log is part of.
+    pub block: u64,
+}
+
+/// Sends transactions signed by clients to the node. The SDK client can only send
+/// transactions signed with our own wallet, so this talks JSON-RPC directly.
+pub struct Relay {
+    http: reqwest::Client,
+    url: Url,
+}
+
+impl Relay {
+    pub fn new(url: Url) -> Self {
+        Relay {
+            http: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, AppError> {
+        let response: Value = self
+            .http
+            .post(self.url.clone())
+            .json(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .send()
+            .await
+            .map_err(AppError::rpc)?
+            .json()
+            .await
+            .map_err(AppError::rpc)?;
+        if let Some(error) = response.get("error") {
+            return Err(AppError::Upstream(format!("{} failed: {}", method, error)));
+        }
+        Ok(response["result"].clone())
+    }
+
+    pub async fn chain_id(&self) -> Result<u64, AppError> {
+        let result = self.call("eth_chainId", json!([])).await?;
+        parse_quantity(&result).ok_or_else(|| AppError::Upstream(format!("Invalid eth_chainId result: {}", result)))
+    }
+
+    /// Submits a signed transaction and returns its hash.
+    pub async fn send(&self, raw: &[u8]) -> Result<B256, AppError> {
+        let result = self
+            .call("eth_sendRawTransaction", json!([format!("0x{}", hex::encode(raw))]))
+            .await?;
+        result
+            .as_str()
+            .and_then(|hash| hash.parse().ok())
+            .ok_or_else(|| AppError::Upstream(format!("Invalid eth_sendRawTransaction result: {}", result)))
+    }
+
+    /// Waits until the transaction `tx_hash` is in a block, and returns the entities it stored.
+    pub async fn wait_for_stored(&self, tx_hash: B256) -> Result<Vec<Stored>, AppError> {
+        let started = Instant::now();
+        let receipt = loop {
+            let receipt = self.call("eth_getTransactionReceipt", json!([tx_hash.to_string()])).await?;
+            if !receipt.is_null() {
+                break receipt;
+            }
+            if started.elapsed() > RECEIPT_TIMEOUT {
+                return Err(AppError::Upstream(format!("Transaction {} wasn't included in a block in time.", tx_hash)));
+            }
+            tokio::time::sleep(RECEIPT_POLL_INTERVAL).await;
+        };
+        if receipt["status"].as_str() != Some("0x1") {
+            return Err(AppError::Upstream(format!("Transaction {} failed.", tx_hash)));
+        }
+
+        let stored = receipt["logs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(parse_storage_log)
+            .filter(|log| matches!(log.change, StorageChange::Created | StorageChange::Updated))
+            .filter_map(|log| {
+                Some(Stored {
+                    entity_key: log.entity_key,
+                    expires_at_block: log.expires_at_block?,
+                })
+            })
+            .collect();
+        Ok(stored)
+    }
+
+    /// The storage logs of the blocks `from_block..=to_block`, in the order they were logged.
+    /// Ranges longer than `MAX_LOG_RANGE` blocks are fetched in several calls.
+    pub async fn storage_logs(&self, from_block: u64, to_block: u64) -> Result<Vec<StorageLog>, AppError> {
+        let topics: Vec<String> = [ENTITY_CREATED_EVENT, ENTITY_UPDATED_EVENT, ENTITY_DELETED_EVENT, ENTITY_EXTENDED_EVENT]
+            .iter()
+            .map(|event| keccak256(event).to_string())
+            .collect();
+        let mut logs = vec![];
+        let mut from = from_block;
+        while from <= to_block {
+            let to = to_block.min(from + MAX_LOG_RANGE - 1);
+            let filter = json!({
+                "address": STORAGE_ADDRESS.to_string(),
+                "fromBlock": format!("0x{:x}", from),
+                "toBlock": format!("0x{:x}", to),
+                "topics": [topics],
+            });
+            let result = self.call("eth_getLogs", json!([filter])).await?;
+            let found = result
+                .as_array()
+                .ok_or_else(|| AppError::Upstream(format!("Invalid eth_getLogs result: {}", result)))?;
+            logs.extend(found.iter().filter_map(parse_storage_log));
+            from = to + 1;
+        }
+        Ok(logs)
+    }
+}
+
+/// Decodes a log of the storage processor, skipping any other.
+fn parse_storage_log(log: &Value) -> Option<StorageLog> {
+    let topics: Vec<B256> = log["topics"]
+        .as_array()?
+        .iter()
+        .filter_map(|topic| topic.as_str()?.parse().ok())
+        .collect();
+    if topics.len() < 2 {
+        return None;
+    }
+    let change = match topics[0] {
+        topic if topic == keccak256(ENTITY_CREATED_EVENT) => StorageChange::Created,
+        topic if topic == keccak256(ENTITY_UPDATED_EVENT) => StorageChange::Updated,
+        topic if topic == keccak256(ENTITY_DELETED_EVENT) => StorageChange::Deleted,
+        topic if topic == keccak256(ENTITY_EXTENDED_EVENT) => StorageChange::Extended,
+        _ => return None,
+    };
+    let data = hex::decode(log["data"].as_str()?.trim_start_matches("0x")).ok()?;
+    let expires_at_block = match change {
+        StorageChange::Deleted => None,
+        _ => Some(U256::try_from_be_slice(data.rchunks(32).next()?)?.try_into().ok()?),
+    };
+    Some(StorageLog {
+        change,
+        entity_key: topics[1],
+        expires_at_block,
+        block: parse_quantity(&log["blockNumber"])?,
+    })
+}
+
+fn parse_quantity(value: &Value) -> Option<u64> {
+    u64::from_str_radix(value.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+/// The transaction a client is asked to sign, in the shape wallets expect.
+pub fn unsigned_transaction(data: &[u8], chain_id: u64) -> Value {
+    json!({
+        "to": STORAGE_ADDRESS.to_string(),
+        "data": format!("0x{}", hex::encode(data)),
+        "value": "0x0",
+        "chainId": format!("0x{:x}", chain_id),
+    })
+}
+
+/// Where a client-signed upload stands.
+pub enum Stage {
+    /// Waiting for the transaction that creates the provisional main entity.
+    Main,
+    /// Waiting for the transactions that store the remaining chunks and the thumbnails
+    /// and then turn the main entity into a real image.
+    Parts { main_entity_key: Hash },
+}
+
+/// An upload that was prepared by `POST /signed-uploads` and is waiting for its owner to
+/// sign the transactions. It keeps everything needed to prepare the next ones.
+pub struct PendingUpload {
+    pub owner: Address,
+    pub chain_id: u64,
+    pub stage: Stage,
+    /// The hash of the input of every transaction the owner has to sign next, in order.
+    pub expected: Vec<B256>,
+    pub btl: u64,
+    /// The image as it is stored, spooled to a temporary file, and its size.
+    pub data: File,
+    pub size: usize,
+    pub mime_type: String,
+    pub filename: String,
+    pub tags: Vec<String>,
+    pub identity: Option<Identity>,
+    pub uploaded_at: u64,
+    /// The final annotations of the main entity, set by its last transaction.
+    pub string_annotations: Vec<Annotation<String>>,
+    pub numeric_annotations: Vec<Annotation<u64>>,
+    pub rendered: Vec<Rendered>,
+    /// Reported back once the upload is done.
+    pub width: u64,
+    pub height: u64,
+    pub exif_annotations: Vec<Annotation<String>>,
+    pub metadata_stripped: bool,
+}
+
+impl PendingUpload {
+    /// Reads the spooled image back.
+    pub async fn read_data(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.size);
+        self.data.seek(SeekFrom::Start(0)).await?;
+        self.data.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// The bytes of image and thumbnails this upload holds on to while it waits.
+    fn held(&self) -> usize {
+        self.size + self.rendered.iter().map(|rendered| rendered.data.len()).sum::<usize>()
+    }
+}
+
+/// Client-signed uploads that are waiting for their signed transactions. Kept in memory (and
+/// temporary files) only, so they are lost when the server restarts, and dropped `PENDING_TTL`
+/// after their last step.
+#[derive(Default)]
+pub struct PendingUploads {
+    uploads: Mutex<HashMap<String, (Instant, PendingUpload)>>,
+    counter: AtomicU64,
+}
+
+impl PendingUploads {
+    /// Stores `upload` and returns the id under which it can be continued. The id doesn't need
+    /// to be secret: every transaction submitted for it must be signed by its owner.
+    pub fn insert(&self, upload: PendingUpload) -> Result<String, AppError> {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, (stored_at, _)| stored_at.elapsed() < PENDING_TTL);
+        if upload.held() > MAX_PENDING_BYTES {
+            return Err(AppError::PayloadTooLarge(format!(
+                "A signed upload may hold at most {} bytes of image and thumbnails.",
+                MAX_PENDING_BYTES
+            )));
+        }
+        let held: usize = uploads.values().map(|(_, pending)| pending.held()).sum();
+        if uploads.len() >= MAX_PENDING_UPLOADS || held + upload.held() > MAX_PENDING_BYTES {
+            return Err(AppError::TooManyRequests(
+                "Too many signed uploads are waiting for their transactions, try again later.".to_string(),
+                60,
+            ));
+        }
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let mut hasher = Sha256::new();
+        hasher.update(nanos.to_be_bytes());
+        hasher.update(self.counter.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        hasher.update(upload.owner);
+        let id = hex::encode(&hasher.finalize()[..16]);
+        uploads.insert(id.clone(), (Instant::now(), upload));
+        Ok(id)
+    }
+
+    /// Removes the upload `id` while its transactions are processed, so it can't be submitted
+    /// twice at the same time. Put it back with `insert_again` if it isn't done yet.
+    pub fn take(&self, id: &str) -> Result<PendingUpload, AppError> {
+        let mut uploads = self.uploads.lock().unwrap();
+        match uploads.remove(id) {
+            Some((stored_at, upload)) if stored_at.elapsed() < PENDING_TTL => Ok(upload),
+            _ => Err(AppError::NotFound(format!("No signed upload {} is waiting.", id))),
+        }
+    }
+
+    pub fn insert_again(&self, id: String, upload: PendingUpload) {
+        self.uploads.lock().unwrap().insert(id, (Instant::now(), upload));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_consensus::{crypto::secp256k1::sign_message, SignableTransaction, TxEip1559};
+    use alloy_eips::eip2718::Encodable2718;
+    use alloy_primitives::{b256, TxKind};
+
+    const CHAIN_ID: u64 = 60138453;
+
+    /// The secret keys 1 and 2, and the addresses they belong to.
+    const OWNER_KEY: B256 = b256!("0x0000000000000000000000000000000000000000000000000000000000000001");
+    const OWNER: Address = address!("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf");
+    const OTHER_KEY: B256 = b256!("0x0000000000000000000000000000000000000000000000000000000000000002");
+
+    fn unhex(parts: &[&str]) -> Vec<u8> {
+        hex::decode(parts.concat()).unwrap()
+    }
+
+    fn create(btl: u64, data: &[u8]) -> Create {
+        Create {
+            btl,
+            data: Bytes::copy_from_slice(data),
+            ..Default::default()
+        }
+    }
+
+    fn sign(key: B256, tx: TxEip1559) -> Vec<u8> {
+        let signature = sign_message(key, tx.signature_hash()).unwrap();
+        TxEnvelope::from(tx.into_signed(signature)).encoded_2718()
+    }
+
+    fn storage_tx(chain_id: u64, input: &[u8]) -> TxEip1559 {
+        TxEip1559 {
+            chain_id,
+            nonce: 7,
+            gas_limit: 1_000_000,
+            max_fee_per_gas: 2_000_000_000,
+            max_priority_fee_per_gas: 1_000_000_000,
+            to: TxKind::Call(STORAGE_ADDRESS),
+            value: U256::ZERO,
+            access_list: Default::default(),
+            input: Bytes::copy_from_slice(input).into(),
+        }
+    }
+
+    #[test]
+    fn encodes_an_empty_transaction() {
+        assert_eq!(encode_transaction(&[], &[])[..], unhex(&["c4", "c0", "c0", "c0", "c0"]));
+    }
+
+    #[test]
+    fn encodes_a_create_with_annotations() {
+        let mut create = create(1, &[0xab]);
+        create.string_annotations.push(Annotation::new("k", "v"));
+        create.numeric_annotations.push(Annotation::new("n", 2u64));
+        let expected = unhex(&[
+            "d0", // [creates, updates, deletes, extensions]
+            "cc", // creates
+            "cb", // [btl, data, string_annotations, numeric_annotations]
+            "01",
+            "81ab",
+            "c3c26b76", // [["k", "v"]]
+            "c3c26e02", // [["n", 2]]
+            "c0c0c0",
+        ]);
+        assert_eq!(encode_transaction(&[create], &[])[..], expected);
+    }
+
+    #[test]
+    fn encodes_an_update_with_its_key() {
+        let update = Update {
+            entity_key: Hash::repeat_byte(0x11),
+            ..Default::default()
+        };
+        let expected = unhex(&[
+            "ea", // [creates, updates, deletes, extensions]
+            "c0",
+            "e6", // updates
+            "e5", // [key, btl, data, string_annotations, numeric_annotations]
+            "a0",
+            &"11".repeat(32),
+            "80", // btl 0
+            "80", // no data
+            "c0c0",
+            "c0c0",
+        ]);
+        assert_eq!(encode_transaction(&[], &[update])[..], expected);
+    }
+
+    #[test]
+    fn encodes_long_items_with_long_headers() {
+        let expected = unhex(&[
+            "f844", // [creates, updates, deletes, extensions]
+            "f83f", // creates
+            "f83d", // [btl, data, string_annotations, numeric_annotations]
+            "01",
+            "b838",
+            &"00".repeat(56),
+            "c0c0",
+            "c0c0c0",
+        ]);
+        assert_eq!(encode_transaction(&[create(1, &[0; 56])], &[])[..], expected);
+    }
+
+    #[test]
+    fn accepts_the_prepared_transaction() {
+        let input = encode_transaction(&[create(1, b"image")], &[]);
+        let raw = sign(OWNER_KEY, storage_tx(CHAIN_ID, &input));
+        assert!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)).is_ok());
+    }
+
+    #[test]
+    fn rejects_another_signer() {
+        let input = encode_transaction(&[create(1, b"image")], &[]);
+        let raw = sign(OTHER_KEY, storage_tx(CHAIN_ID, &input));
+        assert!(matches!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)), Err(AppError::Forbidden(_))));
+    }
+
+    #[test]
+    fn rejects_another_chain() {
+        let input = encode_transaction(&[create(1, b"image")], &[]);
+        let raw = sign(OWNER_KEY, storage_tx(1, &input));
+        assert!(matches!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_other_operations() {
+        let input = encode_transaction(&[create(1, b"image")], &[]);
+        let other = encode_transaction(&[create(1, b"other")], &[]);
+        let raw = sign(OWNER_KEY, storage_tx(CHAIN_ID, &other));
+        assert!(matches!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)), Err(AppError::BadRequest(_))));
+
+        let mut tx = storage_tx(CHAIN_ID, &input);
+        tx.to = TxKind::Call(Address::repeat_byte(0x42));
+        let raw = sign(OWNER_KEY, tx);
+        assert!(matches!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)), Err(AppError::BadRequest(_))));
+
+        let mut tx = storage_tx(CHAIN_ID, &input);
+        tx.value = U256::from(1);
+        let raw = sign(OWNER_KEY, tx);
+        assert!(matches!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_a_tampered_transaction() {
+        let input = encode_transaction(&[create(1, b"image")], &[]);
+        let mut raw = sign(OWNER_KEY, storage_tx(CHAIN_ID, &input));
+        let at = raw.windows(5).position(|window| window == b"image").unwrap();
+        raw[at..at + 5].copy_from_slice(b"other");
+        let tampered = encode_transaction(&[create(1, b"other")], &[]);
+        // The signature no longer matches the content, so it recovers some other address.
+        assert!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&tampered)).is_err());
+        assert!(check_signed(&raw, OWNER, CHAIN_ID, keccak256(&input)).is_err());
+    }
+
+    fn pending(size: usize) -> PendingUpload {
+        PendingUpload {
+            owner: OWNER,
+            chain_id: CHAIN_ID,
+            stage: Stage::Main,
+            expected: vec![],
+            btl: 1,
+            data: File::from_std(tempfile::tempfile().unwrap()),
+            size,
+            mime_type: "image/png".to_string(),
+            filename: "image.png".to_string(),
+            tags: vec![],
+            identity: None,
+            uploaded_at: 0,
+            string_annotations: vec![],
+            numeric_annotations: vec![],
+            rendered: vec![Rendered { data: vec![0; 100], width: 1, height: 1 }],
+            width: 1,
+            height: 1,
+            exif_annotations: vec![],
+            metadata_stripped: false,
+        }
+    }
+
+    #[test]
+    fn limits_the_bytes_held_by_pending_uploads() {
+        let uploads = PendingUploads::default();
+        assert!(matches!(uploads.insert(pending(MAX_PENDING_BYTES)), Err(AppError::PayloadTooLarge(_))));
+
+        let half = MAX_PENDING_BYTES / 2 - 100;
+        let id = uploads.insert(pending(half)).unwrap();
+        uploads.insert(pending(half)).unwrap();
+        assert!(matches!(uploads.insert(pending(1)), Err(AppError::TooManyRequests(..))));
+
+        // Taking an upload frees its bytes.
+        let _ = uploads.take(&id).unwrap();
+        uploads.insert(pending(1)).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::error::AppError;
+use golem_base_sdk::entity::Annotation;
+
+/// The most tags an image may have, and the longest a tag may be (in characters).
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 64;
+
+/// Splits a comma-separated tag list into normalized tags: trimmed, lower-cased,
+/// without empty entries or duplicates, in the order they were given.
+///
+/// Each tag is stored as its own `tag` annotation, so `tag="sunset"` matches exactly the
+/// images tagged "sunset" and nothing else.
+pub fn normalize_tags<'a>(input: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    for tag in input.into_iter().flat_map(|value| value.split(',')) {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Checks normalized tags given by a client against `MAX_TAGS` and `MAX_TAG_LEN`. Tags that are
+/// already stored are never checked again, so tightening the limits doesn't break old images.
+pub fn check_tags(tags: &[String]) -> Result<(), AppError> {
+    if tags.len() > MAX_TAGS {
+        return Err(AppError::BadRequest(format!("An image can have at most {} tags, got {}.", MAX_TAGS, tags.len())));
+    }
+    if let Some(tag) = tags.iter().find(|tag| tag.chars().count() > MAX_TAG_LEN) {
+        return Err(AppError::BadRequest(format!("Tags can be at most {} characters long: {:?}.", MAX_TAG_LEN, tag)));
+    }
+    Ok(())
+}
+
+/// Normalizes a single search term the same way tags are normalized when stored.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// One `tag` annotation per tag.
+pub fn tag_annotations(tags: &[String]) -> Vec<Annotation<String>> {
+    tags.iter().map(|tag| Annotation::new("tag", tag.clone())).collect()
+}
+
+/// Returns the tags stored in `annotations`, in the order they appear.
+pub fn stored_tags(annotations: &[Annotation<String>]) -> Vec<String> {
+    annotations
+        .iter()
+        .filter(|annot| annot.key == "tag")
+        .map(|annot| annot.value.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_and_whitespace() {
+        assert_eq!(normalize_tags(Some(" Sunset, BEACH ,nature")), vec!["sunset", "beach", "nature"]);
+        assert_eq!(normalize_tag("  Ölfeld "), "ölfeld");
+    }
+
+    #[test]
+    fn drops_empty_tags_and_duplicates() {
+        assert_eq!(normalize_tags(Some("a,,b, ,A,b,")), vec!["a", "b"]);
+        assert!(normalize_tags(Some("")).is_empty());
+        assert!(normalize_tags(None).is_empty());
+        // Repeated inputs (such as stored `tag` annotations) are merged in order.
+        assert_eq!(normalize_tags(["b", "a, B", "c"]), vec!["b", "a", "c"]);
+    }
+
+    #[test]
+    fn limits_the_number_and_length_of_tags() {
+        let tags: Vec<String> = (0..MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert!(check_tags(&tags).is_ok());
+        let too_many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{}", i)).collect();
+        assert!(matches!(check_tags(&too_many), Err(AppError::BadRequest(_))));
+
+        assert!(check_tags(&["é".repeat(MAX_TAG_LEN)]).is_ok());
+        assert!(matches!(check_tags(&["x".repeat(MAX_TAG_LEN + 1)]), Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn reads_and_writes_tag_annotations() {
+        let tags = normalize_tags(Some("b,a"));
+        let mut annotations = tag_annotations(&tags);
+        annotations.insert(0, Annotation::new("filename", "x.png".to_string()));
+        assert_eq!(stored_tags(&annotations), vec!["b", "a"]);
+    }
+}
@@ -0,0 +1,310 @@
+use golem_base_sdk::{
+    entity::{Annotation, Create, Update},
+    GolemBaseClient, Hash,
+};
+use crate::{config::Config, download::sha256_hex};
+use image::{DynamicImage, ImageDecoder, ImageReader, Limits};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, Seek, SeekFrom};
+
+/// The most memory the decoder may allocate, so a huge upload can't balloon the process.
+const THUMBNAIL_DECODE_MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+pub type UploadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How many bytes of an upload are read before its format is detected.
+/// Enough for the signatures of every format `image` knows about.
+pub const SNIFF_LEN: usize = 32;
+
+/// The image types we can decode, and so the most that `allowed_types` may list.
+pub const SUPPORTED_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+/// Detects the format of an upload from the magic bytes at its start, returning its MIME type.
+pub fn sniff_mime_type(head: &[u8]) -> Option<&'static str> {
+    image::guess_format(head).ok().map(|format| format.to_mime_type())
+}
+
+/// Turns the bytes of an uploaded file into chunk entities while they are still arriving.
+/// Apart from the first chunk, which we keep until the upload is finalized, no more than
+/// `chunk_size` bytes of the file are held in memory at once.
+///
+/// The first chunk becomes the main image entity. Chunks 2..N point at it through their
+/// `parent` annotation, so as soon as a second chunk exists we create the main entity with
+/// a provisional `type=image_upload` (invisible to the gallery queries) and then send every
+/// further chunk as soon as it fills. `finish` updates the main entity with its final
+/// annotations and `part_of` count, which is only known once the stream has ended.
+///
+/// Every chunk carries the SHA-256 of its data as `chunk_sha256`, and the main entity also
+/// gets the SHA-256 of the whole file as `sha256`, so readers can verify the reassembled image.
+pub struct ChunkedUpload<'a> {
+    client: &'a GolemBaseClient,
+    config: &'a Config,
+    btl: u64,
+    mime_type: String,
+    buffer: Vec<u8>,
+    first_chunk: Option<Vec<u8>>,
+    main_entity_key: Option<Hash>,
+    parts: u64,
+    total_len: usize,
+    hasher: Sha256,
+    created: Vec<Hash>,
+    expires_at_block: Option<u64>,
+}
+
+/// The result of a successful `ChunkedUpload::finish`.
+pub struct FinishedUpload {
+    pub main_entity_key: Hash,
+    pub parts: u64,
+    /// The block at which the first of the image's entities expires.
+    pub expires_at_block: u64,
+}
+
+impl<'a> ChunkedUpload<'a> {
+    /// Starts an upload whose entities live for `btl` blocks.
+    pub fn new(client: &'a GolemBaseClient, config: &'a Config, btl: u64, mime_type: String) -> Self {
+        ChunkedUpload {
+            client,
+            config,
+            btl,
+            mime_type,
+            buffer: Vec::with_capacity(config.chunk_size),
+            first_chunk: None,
+            main_entity_key: None,
+            parts: 0,
+            total_len: 0,
+            hasher: Sha256::new(),
+            created: vec![],
+            expires_at_block: None,
+        }
+    }
+
+    /// Keeps track of the earliest expiry among the entities created so far.
+    fn record_expiry(&mut self, expiration_block: u64) {
+        self.expires_at_block = Some(match self.expires_at_block {
+            Some(block) => block.min(expiration_block),
+            None => expiration_block,
+        });
+    }
+
+    /// Total number of bytes received so far.
+    pub fn total_len(&self) -> usize {
+        self.total_len
+    }
+
+    /// Appends data from the request body, submitting every chunk that fills up.
+    pub async fn push(&mut self, mut data: &[u8]) -> Result<(), UploadError> {
+        self.total_len += data.len();
+        self.hasher.update(data);
+        while !data.is_empty() {
+            let chunk_size = self.config.chunk_size;
+            let take = (chunk_size - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.buffer.len() == chunk_size {
+                let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(chunk_size));
+                self.submit_chunk(chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn submit_chunk(&mut self, chunk: Vec<u8>) -> Result<(), UploadError> {
+        self.parts += 1;
+
+        // Hold on to the first chunk: if it turns out to be the only one, the main
+        // entity can be created in one go by `finish`.
+        if self.parts == 1 {
+            self.first_chunk = Some(chunk);
+            return Ok(());
+        }
+
+        let main_entity_key = match self.main_entity_key {
+            Some(key) => key,
+            None => self.create_provisional_main().await?,
+        };
+
+        let chunk_create = chunk_create(self.config, self.btl, main_entity_key, &self.mime_type, self.parts, chunk);
+        let receipts = self.client.create_entities(vec![chunk_create]).await?;
+        println!("Created chunk {}: {:?}", self.parts, receipts[0].entity_key);
+        self.created.push(receipts[0].entity_key);
+        self.record_expiry(receipts[0].expiration_block);
+        Ok(())
+    }
+
+    async fn create_provisional_main(&mut self) -> Result<Hash, UploadError> {
+        let first_chunk = self.first_chunk.clone().unwrap_or_default();
+        let main_entity_create = provisional_main_create(self.config, self.btl, &self.mime_type, first_chunk);
+        let receipts = self.client.create_entities(vec![main_entity_create]).await?;
+        let key = receipts[0].entity_key;
+        println!("Created provisional main entity: {:?}", key);
+        self.main_entity_key = Some(key);
+        self.created.push(key);
+        Ok(key)
+    }
+
+    /// Submits the last partial chunk and writes the final annotations onto the main entity.
+    /// The layout annotations (`part_of`, `size`, checksums, ...) are added to the given ones.
+    pub async fn finish(
+        &mut self,
+        mut string_annotations: Vec<Annotation<String>>,
+        mut numeric_annotations: Vec<Annotation<u64>>,
+    ) -> Result<FinishedUpload, UploadError> {
+        if !self.buffer.is_empty() || self.parts == 0 {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.submit_chunk(chunk).await?;
+        }
+
+        let first_chunk = self.first_chunk.take().unwrap_or_default();
+        add_layout_annotations(
+            self.config,
+            &mut string_annotations,
+            &mut numeric_annotations,
+            Layout {
+                parts: self.parts,
+                size: self.total_len,
+                sha256: hex::encode(self.hasher.clone().finalize()),
+                first_chunk: &first_chunk,
+            },
+        );
+
+        let main_entity_key = match self.main_entity_key {
+            // Single-chunk image: nothing was created yet.
+            None => {
+                let receipts = self
+                    .client
+                    .create_entities(vec![Create {
+                        data: first_chunk.into(),
+                        btl: self.btl,
+                        string_annotations,
+                        numeric_annotations,
+                    }])
+                    .await?;
+                let key = receipts[0].entity_key;
+                self.main_entity_key = Some(key);
+                self.created.push(key);
+                self.record_expiry(receipts[0].expiration_block);
+                key
+            }
+            // Multi-chunk image: turn the provisional entity into a real image.
+            Some(key) => {
+                let receipts = self
+                    .client
+                    .update_entities(vec![Update {
+                        entity_key: key,
+                        data: first_chunk.into(),
+                        btl: self.btl,
+                        string_annotations,
+                        numeric_annotations,
+                    }])
+                    .await?;
+                self.record_expiry(receipts[0].expiration_block);
+                key
+            }
+        };
+        println!("Finalized main entity {:?} with {} parts", main_entity_key, self.parts);
+        Ok(FinishedUpload {
+            main_entity_key,
+            parts: self.parts,
+            expires_at_block: self.expires_at_block.unwrap_or_default(),
+        })
+    }
+
+    /// Counts an entity that was created for this upload elsewhere (such as a thumbnail), so
+    /// `rollback` deletes it too.
+    pub fn track(&mut self, entity_key: Hash) {
+        self.created.push(entity_key);
+    }
+
+    /// Deletes every entity created by this upload.
+    pub async fn rollback(&self) {
+        if self.created.is_empty() {
+            return;
+        }
+        match self.client.delete_entities(self.created.clone()).await {
+            Ok(_) => println!("Rolled back {} entities.", self.created.len()),
+            Err(e) => eprintln!("Error rolling back upload {:?}: {:?}", self.created, e),
+        }
+    }
+}
+
+/// The main entity of an image whose other chunks are still being stored. Its `type=image_upload`
+/// keeps it out of the gallery until it is updated with its final annotations.
+pub fn provisional_main_create(config: &Config, btl: u64, mime_type: &str, first_chunk: Vec<u8>) -> Create {
+    let chunk_sha256 = sha256_hex(&first_chunk);
+    Create {
+        data: first_chunk.into(),
+        btl,
+        string_annotations: vec![
+            Annotation::new("type", "image_upload"),
+            Annotation::new("app", config.app_tag.clone()),
+            Annotation::new("mime_type", mime_type.to_string()),
+            Annotation::new("chunk_sha256", chunk_sha256),
+        ],
+        numeric_annotations: vec![Annotation::new("part", 1u64)],
+    }
+}
+
+/// A chunk entity holding part 2..N of the image `parent`.
+pub fn chunk_create(config: &Config, btl: u64, parent: Hash, mime_type: &str, part: u64, chunk: Vec<u8>) -> Create {
+    let chunk_sha256 = sha256_hex(&chunk);
+    Create {
+        data: chunk.into(),
+        btl,
+        string_annotations: vec![
+            Annotation::new("parent", parent.to_string()),
+            Annotation::new("type", "image_chunk"),
+            Annotation::new("app", config.app_tag.clone()),
+            Annotation::new("mime_type", mime_type.to_string()),
+            Annotation::new("chunk_sha256", chunk_sha256),
+        ],
+        // The total number of parts is only known at the end, so it lives on the main entity.
+        numeric_annotations: vec![Annotation::new("part", part)],
+    }
+}
+
+/// How a finished image is split into chunks, as recorded on its main entity.
+pub struct Layout<'a> {
+    pub parts: u64,
+    /// The size of the whole image in bytes.
+    pub size: usize,
+    /// The SHA-256 of the whole image, hex encoded.
+    pub sha256: String,
+    /// The data of the main entity itself.
+    pub first_chunk: &'a [u8],
+}
+
+/// Adds the annotations that describe `layout` to those of a main image entity.
+pub fn add_layout_annotations(
+    config: &Config,
+    string_annotations: &mut Vec<Annotation<String>>,
+    numeric_annotations: &mut Vec<Annotation<u64>>,
+    layout: Layout,
+) {
+    // `size` and `chunk_size` let readers map byte ranges onto parts without fetching them.
+    numeric_annotations.extend([
+        Annotation::new("part", 1u64),
+        Annotation::new("part_of", layout.parts),
+        Annotation::new("size", layout.size as u64),
+        Annotation::new("chunk_size", config.chunk_size as u64),
+    ]);
+    string_annotations.push(Annotation::new("sha256", layout.sha256));
+    string_annotations.push(Annotation::new("chunk_sha256", sha256_hex(layout.first_chunk)));
+}
+
+/// Decodes the spooled upload so its renditions can be made, turned upright according to
+/// its EXIF orientation (phones store photos as shot and only record the rotation).
+/// Runs on a blocking thread, and the decoder is capped by `THUMBNAIL_DECODE_MAX_ALLOC`.
+pub fn decode_upload(mut file: impl BufRead + Seek) -> Result<DynamicImage, UploadError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(THUMBNAIL_DECODE_MAX_ALLOC);
+
+    let mut reader = ImageReader::new(file).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}